@@ -1,8 +1,11 @@
 use crate::ir::ast::Function;
 use crate::ir::ast::Name;
 use crate::ir::ast::ValueConstructor;
+use crate::stdlib::subprocess::SubprocessContext;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::LinkedList;
+use std::rc::Rc;
 
 #[derive(Clone)]
 pub struct Scope<A> {
@@ -54,6 +57,8 @@ impl<A: Clone> Scope<A> {
 pub struct Environment<A> {
     pub globals: Scope<A>,
     pub stack: LinkedList<Scope<A>>,
+    // Shared by every clone of the environment, so spawned processes survive scope changes
+    pub subprocess: Rc<RefCell<SubprocessContext>>,
 }
 
 impl<A: Clone> Environment<A> {
@@ -61,6 +66,7 @@ impl<A: Clone> Environment<A> {
         Environment {
             globals: Scope::new(),
             stack: LinkedList::new(),
+            subprocess: Rc::new(RefCell::new(SubprocessContext::new())),
         }
     }
 
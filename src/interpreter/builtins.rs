@@ -1,11 +1,47 @@
 use crate::environment::environment::Environment;
 use crate::ir::ast::{Expression, Name};
-use crate::stdlib::{run_command, run_shell_command, RunOptions};
+use crate::stdlib::{
+    explain_exit_code, kill_pid, pkill, popen_command, run_commands_parallel,
+    run_pipeline, spawn_detached, stream_lines, wait_pid,
+    CompletedProcess,
+    OutputSink, RunOptions, StdioTarget, SubprocessError,
+};
 use super::expression_eval::ExpressionResult;
+use base64::Engine;
+use sha2::{Digest, Sha256};
 
-/// Represents a built-in function that can be called from RPython
+/// Represents a built-in function that can be called from RPython.
+/// A failure that scripts should be able to inspect is returned as a value, e.g.
+/// `ExpressionResult::Propagate(Expression::CErr(record))` to propagate a structured
+/// error like `Err(record)` would; `Err(String)` aborts evaluation with a message.
 pub type BuiltinFunction = fn(Vec<Expression>, &Environment<Expression>) -> Result<ExpressionResult, String>;
 
+/// Why a built-in call produced no result
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuiltinError {
+    /// No built-in is registered under the name
+    NotFound(Name),
+    /// The built-in failed with a runtime error
+    Runtime(String),
+}
+
+impl std::fmt::Display for BuiltinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuiltinError::NotFound(name) => write!(f, "No built-in function named '{}'", name),
+            BuiltinError::Runtime(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BuiltinError {}
+
+impl From<BuiltinError> for String {
+    fn from(err: BuiltinError) -> Self {
+        err.to_string()
+    }
+}
+
 /// Registry of built-in functions
 pub struct BuiltinRegistry {
     functions: std::collections::HashMap<Name, BuiltinFunction>,
@@ -30,18 +66,41 @@ impl BuiltinRegistry {
     }
 }
 
-/// Global builtin registry instance using std::sync::OnceLock for thread-safe initialization
-static BUILTIN_REGISTRY: std::sync::OnceLock<BuiltinRegistry> = std::sync::OnceLock::new();
+/// Global builtin registry instance using std::sync::OnceLock for thread-safe initialization.
+/// The RwLock lets built-ins be replaced at runtime (see `reregister`).
+static BUILTIN_REGISTRY: std::sync::OnceLock<std::sync::RwLock<BuiltinRegistry>> = std::sync::OnceLock::new();
 
 /// Get the global builtin registry (thread-safe initialization)
-fn get_builtin_registry() -> &'static BuiltinRegistry {
+fn builtin_registry_lock() -> &'static std::sync::RwLock<BuiltinRegistry> {
     BUILTIN_REGISTRY.get_or_init(|| {
         let mut registry = BuiltinRegistry::new();
         register_subprocess_run(&mut registry);
-        registry
+        register_io_builtins(&mut registry);
+        register_os_builtins(&mut registry);
+        register_shutil_builtins(&mut registry);
+        register_time_builtins(&mut registry);
+        std::sync::RwLock::new(registry)
     })
 }
 
+/// Lock the global builtin registry for reading
+fn get_builtin_registry() -> std::sync::RwLockReadGuard<'static, BuiltinRegistry> {
+    // A panic while holding the lock can't leave the map half-updated, so poisoning is ignored
+    builtin_registry_lock()
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Replace (or add) a built-in function at runtime, e.g. to stub one out from
+/// a REPL or a test. Returns the function previously registered under the name.
+pub fn reregister(name: &str, func: BuiltinFunction) -> Option<BuiltinFunction> {
+    builtin_registry_lock()
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .functions
+        .insert(name.to_string(), func)
+}
+
 /// Register all built-in functions with the environment
 pub fn register_builtins(_env: &mut Environment<Expression>) {
     // Built-in functions are handled through the global registry
@@ -49,340 +108,4831 @@ pub fn register_builtins(_env: &mut Environment<Expression>) {
     // the registry in eval_builtin_function
 }
 
-/// Evaluate a built-in function call
+/// Evaluate a built-in function call.
+/// Fails with `BuiltinError::NotFound` when no built-in has this name, so the
+/// caller can look for a user-defined function instead.
 pub fn eval_builtin_function(
     name: &Name,
     args: Vec<Expression>,
     env: &Environment<Expression>,
-) -> Result<Option<ExpressionResult>, String> {
-    let registry = get_builtin_registry();
-    
-    if let Some(builtin_func) = registry.lookup(name) {
-        Ok(Some(builtin_func(args, env)?))
-    } else {
-        Ok(None)
+) -> Result<ExpressionResult, BuiltinError> {
+    // Copy the function out so the lock isn't held while it runs; a built-in
+    // may itself call other built-ins or replace one
+    let builtin_func = get_builtin_registry().lookup(name).copied();
+
+    match builtin_func {
+        Some(builtin_func) => builtin_func(args, env).map_err(BuiltinError::Runtime),
+        None => Err(BuiltinError::NotFound(name.clone())),
     }
 }
 
 /// Register the subprocess.run built-in function
 fn register_subprocess_run(registry: &mut BuiltinRegistry) {
     registry.register("subprocess.run".to_string(), subprocess_run_builtin);
+    registry.register("subprocess.ok".to_string(), subprocess_ok_builtin);
+    registry.register("subprocess.expect_code".to_string(), subprocess_expect_code_builtin);
+    registry.register("subprocess.run_result".to_string(), subprocess_run_result_builtin);
+    registry.register("subprocess.run_int".to_string(), subprocess_run_int_builtin);
+    registry.register("subprocess.run_ints".to_string(), subprocess_run_ints_builtin);
+    registry.register("subprocess.run_bool".to_string(), subprocess_run_bool_builtin);
+    registry.register("subprocess.map".to_string(), subprocess_map_builtin);
+    registry.register("subprocess.pipeline".to_string(), subprocess_pipeline_builtin);
+    registry.register("subprocess.run_bytes".to_string(), subprocess_run_bytes_builtin);
+    registry.register("subprocess.run_outlen".to_string(), subprocess_run_outlen_builtin);
+    registry.register("subprocess.run_throughput".to_string(), subprocess_run_throughput_builtin);
+    registry.register("subprocess.run_progress".to_string(), subprocess_run_progress_builtin);
+    registry.register("subprocess.run_words".to_string(), subprocess_run_words_builtin);
+    registry.register("subprocess.run_firstline".to_string(), subprocess_run_firstline_builtin);
+    registry.register("subprocess.run_combined".to_string(), subprocess_run_combined_builtin);
+    registry.register("subprocess.run_template".to_string(), subprocess_run_template_builtin);
+    registry.register("subprocess.run_with_env".to_string(), subprocess_run_with_env_builtin);
+    registry.register("subprocess.run_jsonl".to_string(), subprocess_run_jsonl_builtin);
+    registry.register("subprocess.run_json_raw".to_string(), subprocess_run_json_raw_builtin);
+    registry.register("subprocess.run_map".to_string(), subprocess_run_map_builtin);
+    registry.register("subprocess.run_to_tmpfile".to_string(), subprocess_run_to_tmpfile_builtin);
+    registry.register("subprocess.run_kv".to_string(), subprocess_run_kv_builtin);
+    registry.register("subprocess.run_csv".to_string(), subprocess_run_csv_builtin);
+    registry.register("subprocess.run_base64".to_string(), subprocess_run_base64_builtin);
+    registry.register("subprocess.run_sha256".to_string(), subprocess_run_sha256_builtin);
+    registry.register("subprocess.run_rusage".to_string(), subprocess_run_rusage_builtin);
+    registry.register("subprocess.run_cputime".to_string(), subprocess_run_cputime_builtin);
+    registry.register("subprocess.run_pty".to_string(), subprocess_run_pty_builtin);
+    registry.register("subprocess.run_strict".to_string(), subprocess_run_strict_builtin);
+    registry.register("subprocess.spawn_detached".to_string(), subprocess_spawn_detached_builtin);
+    registry.register("subprocess.kill_pid".to_string(), subprocess_kill_pid_builtin);
+    registry.register("subprocess.pkill".to_string(), subprocess_pkill_builtin);
+    registry.register("subprocess.wait_pid".to_string(), subprocess_wait_pid_builtin);
+    registry.register("subprocess.explain_code".to_string(), subprocess_explain_code_builtin);
+    registry.register("subprocess.equal".to_string(), subprocess_equal_builtin);
+    registry.register("subprocess.diff".to_string(), subprocess_diff_builtin);
+    registry.register("subprocess.resolve".to_string(), subprocess_resolve_builtin);
+    registry.register("subprocess.was_signaled".to_string(), subprocess_was_signaled_builtin);
+    registry.register("subprocess.args".to_string(), subprocess_args_builtin);
+    registry.register("subprocess.stdout_lossy".to_string(), subprocess_stdout_lossy_builtin);
+    registry.register("subprocess.stderr_lossy".to_string(), subprocess_stderr_lossy_builtin);
+    registry.register("subprocess.last_options".to_string(), subprocess_last_options_builtin);
+    registry.register("subprocess.Popen".to_string(), subprocess_popen_builtin);
+    registry.register("subprocess.finish".to_string(), subprocess_finish_builtin);
+    registry.register("subprocess.join_timeout".to_string(), subprocess_join_timeout_builtin);
+    registry.register("subprocess.is_alive".to_string(), subprocess_is_alive_builtin);
+    registry.register("subprocess.stdin_write".to_string(), subprocess_stdin_write_builtin);
+    registry.register("subprocess.read_until".to_string(), subprocess_read_until_builtin);
+    registry.register("subprocess.stream_lines".to_string(), subprocess_stream_lines_builtin);
+    registry.register("subprocess.next_line".to_string(), subprocess_next_line_builtin);
+    registry.register("subprocess.close_stream".to_string(), subprocess_close_stream_builtin);
+    registry.register("subprocess.reap_all".to_string(), subprocess_reap_all_builtin);
 }
 
-/// Implementation of subprocess.run built-in function
-fn subprocess_run_builtin(
+/// Evaluate the arguments of a built-in call.
+/// Returns `Err` with the propagated result if any argument propagates an error.
+fn evaluate_arguments(
     args: Vec<Expression>,
     env: &Environment<Expression>,
-) -> Result<ExpressionResult, String> {
-    // Validate argument count (1-3 arguments expected)
-    if args.is_empty() || args.len() > 3 {
-        return Err("subprocess.run() takes 1 to 3 arguments".to_string());
-    }
-
-    // Evaluate all arguments first
+) -> Result<Result<Vec<Expression>, ExpressionResult>, String> {
     let mut evaluated_args = Vec::new();
     for arg in args {
         match super::expression_eval::eval(arg, env)? {
             ExpressionResult::Value(expr) => evaluated_args.push(expr),
-            ExpressionResult::Propagate(expr) => return Ok(ExpressionResult::Propagate(expr)),
+            ExpressionResult::Propagate(expr) => {
+                return Ok(Err(ExpressionResult::Propagate(expr)))
+            }
         }
     }
+    Ok(Ok(evaluated_args))
+}
 
-    // Parse the command argument (first argument)
-    let command = match &evaluated_args[0] {
+/// Name of the variant of an expression, e.g. `CInt`, for error messages
+fn variant_name(value: &Expression) -> String {
+    let debug = format!("{:?}", value);
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Parse a command argument (a list of strings or a single string).
+/// A single string is split into arguments with shell quoting rules when
+/// `split_string` is set, i.e. when it won't be run through a shell.
+fn parse_command_argument(
+    value: &Expression,
+    builtin: &str,
+    split_string: bool,
+) -> Result<Vec<String>, String> {
+    match value {
         Expression::ListValue(list) => {
             // Command as list of strings
             let mut cmd_vec = Vec::new();
-            for item in list {
+            for (index, item) in list.iter().enumerate() {
                 match item {
                     Expression::CString(s) => cmd_vec.push(s.clone()),
-                    _ => return Err("subprocess.run() command list must contain only strings".to_string()),
+                    _ => {
+                        return Err(format!(
+                            "{}() command list must contain only strings: element {} is {}, expected string",
+                            builtin,
+                            index,
+                            variant_name(item)
+                        ))
+                    }
                 }
             }
             if cmd_vec.is_empty() {
-                return Err("subprocess.run() command list cannot be empty".to_string());
+                return Err(format!("{}() command list cannot be empty", builtin));
             }
-            cmd_vec
+            Ok(cmd_vec)
+        }
+        Expression::CString(s) if split_string => {
+            let cmd_vec = crate::stdlib::shlex::split(s).map_err(|e| format!("{}() {}", builtin, e))?;
+            if cmd_vec.is_empty() {
+                return Err(format!("{}() command cannot be empty", builtin));
+            }
+            Ok(cmd_vec)
         }
         Expression::CString(s) => {
-            // Single string command (will be used with shell=True)
-            vec![s.clone()]
+            // Single string command, run through the shell as is
+            Ok(vec![s.clone()])
+        }
+        _ => Err(format!("{}() first argument must be a list of strings or a string", builtin)),
+    }
+}
+
+/// Parse a boolean argument of a built-in call
+fn parse_bool_argument(value: &Expression, builtin: &str, arg_name: &str) -> Result<bool, String> {
+    match value {
+        Expression::CTrue => Ok(true),
+        Expression::CFalse => Ok(false),
+        _ => Err(format!("{}() {} argument must be a boolean", builtin, arg_name)),
+    }
+}
+
+/// Apply the keyword options of a built-in call, given as a trailing map from
+/// option name to value, e.g. `{"path_prepend": "/my/bin"}`
+fn apply_keyword_options(value: &Expression, builtin: &str, options: &mut RunOptions) -> Result<(), String> {
+    let entries = match value {
+        Expression::MapValue(entries) => entries,
+        _ => return Err(format!("{}() options argument must be a map", builtin)),
+    };
+
+    for (key, value) in entries {
+        let name = match key {
+            Expression::CString(name) => name.as_str(),
+            _ => return Err(format!("{}() option names must be strings", builtin)),
+        };
+        match (name, value) {
+            ("path_prepend", Expression::CString(dir)) => options.prepend_path(dir),
+            ("path_prepend", _) => {
+                return Err(format!("{}() path_prepend option must be a string", builtin))
+            }
+            ("stderr_file", Expression::CString(path)) => {
+                options.stderr = Some(StdioTarget::File(path.clone()))
+            }
+            ("stderr_file", _) => {
+                return Err(format!("{}() stderr_file option must be a string", builtin))
+            }
+            ("env_keep", Expression::ListValue(names)) => {
+                let names = names
+                    .iter()
+                    .map(|name| match name {
+                        Expression::CString(name) => Ok(name.clone()),
+                        _ => Err(format!("{}() env_keep option must be a list of strings", builtin)),
+                    })
+                    .collect::<Result<Vec<String>, String>>()?;
+                options.env_keep = Some(names);
+            }
+            ("env_keep", _) => {
+                return Err(format!("{}() env_keep option must be a list of strings", builtin))
+            }
+            ("encoding_fallbacks", Expression::ListValue(labels)) => {
+                options.encoding_fallbacks = labels
+                    .iter()
+                    .map(|label| match label {
+                        Expression::CString(label) => Ok(label.clone()),
+                        _ => Err(format!("{}() encoding_fallbacks option must be a list of strings", builtin)),
+                    })
+                    .collect::<Result<Vec<String>, String>>()?;
+            }
+            ("encoding_fallbacks", _) => {
+                return Err(format!("{}() encoding_fallbacks option must be a list of strings", builtin))
+            }
+            ("check", Expression::CTrue) => options.check = true,
+            ("check", Expression::CFalse) => options.check = false,
+            ("check", _) => return Err(format!("{}() check option must be a boolean", builtin)),
+            ("input", Expression::CString(text)) => options.input = Some(text.as_bytes().to_vec()),
+            ("input", Expression::CBytes(bytes)) => options.input = Some(bytes.clone()),
+            ("input", _) => {
+                return Err(format!("{}() input option must be a string or bytes", builtin))
+            }
+            ("stdin", Expression::CString(target)) => {
+                options.stdin = Some(match target.as_str() {
+                    "inherit" => StdioTarget::Inherit,
+                    "null" => StdioTarget::Null,
+                    _ => return Err(format!("{}() stdin option must be 'inherit' or 'null'", builtin)),
+                })
+            }
+            ("stdin", _) => return Err(format!("{}() stdin option must be 'inherit' or 'null'", builtin)),
+            ("input_file", Expression::CString(path)) => options.input_file = Some(path.clone()),
+            ("input_file", _) => {
+                return Err(format!("{}() input_file option must be a string", builtin))
+            }
+            ("split_string", Expression::CTrue) => options.split_string = true,
+            ("split_string", Expression::CFalse) => options.split_string = false,
+            ("split_string", _) => {
+                return Err(format!("{}() split_string option must be a boolean", builtin))
+            }
+            ("merge_stderr", Expression::CTrue) => options.merge_stderr = true,
+            ("merge_stderr", Expression::CFalse) => options.merge_stderr = false,
+            ("merge_stderr", _) => {
+                return Err(format!("{}() merge_stderr option must be a boolean", builtin))
+            }
+            ("cpu_limit", Expression::CInt(seconds)) if *seconds > 0 => {
+                options.cpu_limit_secs = Some(*seconds as u64)
+            }
+            ("cpu_limit", _) => {
+                return Err(format!("{}() cpu_limit option must be a positive number of seconds", builtin))
+            }
+            ("mem_limit", Expression::CInt(bytes)) if *bytes > 0 => options.mem_limit_bytes = Some(*bytes as u64),
+            ("mem_limit", _) => {
+                return Err(format!("{}() mem_limit option must be a positive number of bytes", builtin))
+            }
+            ("pipe_stdin", Expression::CTrue) => options.pipe_stdin = true,
+            ("pipe_stdin", Expression::CFalse) => options.pipe_stdin = false,
+            ("pipe_stdin", _) => {
+                return Err(format!("{}() pipe_stdin option must be a boolean", builtin))
+            }
+            ("new_session", Expression::CTrue) => options.new_session = true,
+            ("new_session", Expression::CFalse) => options.new_session = false,
+            ("new_session", _) => {
+                return Err(format!("{}() new_session option must be a boolean", builtin))
+            }
+            ("strip_ansi", Expression::CTrue) => options.strip_ansi = true,
+            ("strip_ansi", Expression::CFalse) => options.strip_ansi = false,
+            ("strip_ansi", _) => {
+                return Err(format!("{}() strip_ansi option must be a boolean", builtin))
+            }
+            // Silently running as the interpreter's own user would defeat the point
+            ("uid" | "gid", _) if !cfg!(unix) => {
+                return Err(format!("{}() {} option is only supported on Unix", builtin, name))
+            }
+            ("uid", Expression::CInt(uid)) if *uid >= 0 => options.uid = Some(*uid as u32),
+            ("uid", _) => return Err(format!("{}() uid option must be a non-negative integer", builtin)),
+            ("gid", Expression::CInt(gid)) if *gid >= 0 => options.gid = Some(*gid as u32),
+            ("gid", _) => return Err(format!("{}() gid option must be a non-negative integer", builtin)),
+            ("line_prefix", Expression::CString(prefix)) => options.line_prefix = Some(prefix.clone()),
+            ("line_prefix", _) => {
+                return Err(format!("{}() line_prefix option must be a string", builtin))
+            }
+            ("success_codes", Expression::ListValue(codes)) => {
+                options.success_codes = codes
+                    .iter()
+                    .map(|code| match code {
+                        Expression::CInt(code) => Ok(*code),
+                        _ => Err(format!("{}() success_codes option must be a list of integers", builtin)),
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            ("success_codes", _) => {
+                return Err(format!("{}() success_codes option must be a list of integers", builtin))
+            }
+            _ => return Err(format!("{}() got an unexpected option '{}'", builtin, name)),
         }
-        _ => return Err("subprocess.run() first argument must be a list of strings or a string".to_string()),
+    }
+    Ok(())
+}
+
+/// Allow a program to be run by subprocess built-ins once the environment's
+/// allowlist is enforced (see `SubprocessContext::set_enforce_allowlist`)
+pub fn register_allowed_command(env: &Environment<Expression>, name: &str) {
+    env.subprocess.borrow_mut().allow_command(name);
+}
+
+/// Set the options every subprocess built-in run from this environment starts from.
+/// Arguments passed to a call, and the options a built-in needs, take precedence.
+pub fn set_default_run_options(env: &Environment<Expression>, options: RunOptions) {
+    env.subprocess.borrow_mut().set_default_options(options);
+}
+
+/// Set whether subprocess.run calls from this environment that don't pass
+/// `capture_output` capture output, depending on whether they run through the
+/// shell. Takes precedence over the default options for that kind of command.
+pub fn set_default_capture(env: &Environment<Expression>, shell: bool, capture: bool) {
+    env.subprocess.borrow_mut().set_capture_default(shell, Some(capture));
+}
+
+/// Pass every line of output captured by subprocess built-ins run from this
+/// environment to `sink` as well, as it arrives, e.g. to route it to the
+/// embedder's logger
+pub fn set_output_sink(env: &Environment<Expression>, sink: OutputSink) {
+    env.subprocess.borrow_mut().set_output_sink(sink);
+}
+
+/// The default options of the environment, for a built-in to override per call
+fn default_run_options(env: &Environment<Expression>) -> RunOptions {
+    env.subprocess.borrow().default_options().clone()
+}
+
+/// Apply the environment-scoped settings to the options of a run, and record
+/// the result as the options of the most recent call (see subprocess.last_options)
+fn apply_environment_options(env: &Environment<Expression>, options: &mut RunOptions) {
+    let mut context = env.subprocess.borrow_mut();
+    context.apply_allowlist(options);
+    context.apply_output_sink(options);
+    context.record_options(options);
+}
+
+/// Execute a parsed command with the environment's `CommandRunner`.
+/// In shell mode the first element is the script and the rest become its
+/// positional parameters (`$0`, `$1`, ...), as in Python.
+fn execute_command(
+    command: Vec<String>,
+    options: RunOptions,
+    env: &Environment<Expression>,
+) -> Result<CompletedProcess, SubprocessError> {
+    let runner = env.subprocess.borrow().runner();
+    runner.run(command, options)
+}
+
+/// Count the bytes a command writes to stdout with the environment's `CommandRunner`
+fn count_command_output(
+    command: Vec<String>,
+    options: RunOptions,
+    env: &Environment<Expression>,
+) -> Result<u64, SubprocessError> {
+    let runner = env.subprocess.borrow().runner();
+    runner.count_stdout_bytes(command, options)
+}
+
+/// Convert a SubprocessError into an RPython error value (CErr)
+fn subprocess_error_to_expression(subprocess_error: SubprocessError) -> Expression {
+    // Convert SubprocessError to String using the From implementation
+    let error_msg: String = subprocess_error.into();
+    Expression::CErr(Box::new(Expression::CString(error_msg)))
+}
+
+/// Implementation of subprocess.run built-in function
+fn subprocess_run_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    // Validate argument count (1-3 arguments plus an options map expected)
+    if args.is_empty() || args.len() > 4 {
+        return Err("subprocess.run() takes 1 to 3 arguments and an optional options map".to_string());
+    }
+
+    // Evaluate all arguments first
+    let mut evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
     };
 
     // Parse optional arguments (shell and capture_output)
-    let mut options = RunOptions::default();
-    
+    let mut options = default_run_options(env);
+
+    // Trailing map: keyword options
+    if evaluated_args.len() > 1 && matches!(evaluated_args.last(), Some(Expression::MapValue(_))) {
+        let keyword_options = evaluated_args.pop().unwrap_or(Expression::CVoid);
+        apply_keyword_options(&keyword_options, "subprocess.run", &mut options)?;
+    }
+    if evaluated_args.len() > 3 {
+        return Err("subprocess.run() takes 1 to 3 arguments and an optional options map".to_string());
+    }
+
     // Second argument: shell (optional, default False)
     if evaluated_args.len() > 1 {
-        match &evaluated_args[1] {
-            Expression::CTrue => options.shell = true,
-            Expression::CFalse => options.shell = false,
-            _ => return Err("subprocess.run() shell argument must be a boolean".to_string()),
-        }
+        options.shell = parse_bool_argument(&evaluated_args[1], "subprocess.run", "shell")?;
     }
 
-    // Third argument: capture_output (optional, default False)
+    // Third argument: capture_output (optional, default False unless the
+    // environment sets a default for shell or direct commands)
     if evaluated_args.len() > 2 {
-        match &evaluated_args[2] {
-            Expression::CTrue => options.capture_output = true,
-            Expression::CFalse => options.capture_output = false,
-            _ => return Err("subprocess.run() capture_output argument must be a boolean".to_string()),
-        }
+        options.capture_output =
+            parse_bool_argument(&evaluated_args[2], "subprocess.run", "capture_output")?;
+    } else if let Some(capture) = env.subprocess.borrow().capture_default(options.shell) {
+        options.capture_output = capture;
     }
 
-    // Execute the command based on shell option
-    let result = if options.shell && command.len() == 1 {
-        // Shell mode with single string command
-        run_shell_command(command[0].clone(), options)
-    } else if !options.shell {
-        // Direct command execution
-        run_command(command, options)
-    } else {
-        // Shell mode with command list - use first element as shell command
-        run_shell_command(command[0].clone(), options)
-    };
+    // Parse the command argument (first argument)
+    let command = parse_command_argument(&evaluated_args[0], "subprocess.run", options.splits_command_string())?;
+
+    apply_environment_options(env, &mut options);
 
     // Convert result to RPython Expression
-    match result {
+    match execute_command(command, options, env) {
         Ok(completed_process) => {
-            Ok(ExpressionResult::Value(Expression::CompletedProcess {
-                returncode: completed_process.returncode,
-                stdout: completed_process.stdout,
-                stderr: completed_process.stderr,
-            }))
-        }
-        Err(subprocess_error) => {
-            // Convert SubprocessError to String using the From implementation
-            let error_msg: String = subprocess_error.into();
-            // Return error as a Result type (CErr)
-            Ok(ExpressionResult::Value(Expression::CErr(Box::new(
-                Expression::CString(error_msg)
-            ))))
+            Ok(ExpressionResult::Value(completed_process_to_expression(completed_process)))
         }
+        // Return error as a Result type (CErr)
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::environment::environment::Environment;
-
-    fn create_test_env() -> Environment<Expression> {
-        let mut env = Environment::new();
-        register_builtins(&mut env);
-        env
+/// Run the command given by the arguments of a `(cmd[, shell])` style built-in.
+/// Returns `Err` with the result the built-in should return early, either a
+/// propagated error or a CErr when the command cannot be launched.
+fn run_builtin_command(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+    builtin: &str,
+    mut options: RunOptions,
+) -> Result<Result<CompletedProcess, ExpressionResult>, String> {
+    // Validate argument count (1-2 arguments expected)
+    if args.is_empty() || args.len() > 2 {
+        return Err(format!("{}() takes 1 to 2 arguments", builtin));
     }
 
-    #[test]
-    fn test_builtin_registry_creation() {
-        let registry = get_builtin_registry();
-        assert!(registry.lookup(&"subprocess.run".to_string()).is_some());
-    }
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(Err(propagated)),
+    };
 
-    #[test]
-    fn test_eval_builtin_function_exists() {
-        let env = create_test_env();
-        let args = vec![
-            Expression::ListValue(vec![
-                Expression::CString("echo".to_string()),
-                Expression::CString("test".to_string()),
-            ]),
-            Expression::CFalse, // shell=False
-            Expression::CTrue,  // capture_output=True
-        ];
+    // Second argument: shell (optional, default False)
+    if evaluated_args.len() > 1 {
+        options.shell = parse_bool_argument(&evaluated_args[1], builtin, "shell")?;
+    }
+    let command = parse_command_argument(&evaluated_args[0], builtin, options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
 
-        let result = eval_builtin_function(&"subprocess.run".to_string(), args, &env);
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_some());
+    match execute_command(command, options, env) {
+        Ok(completed_process) => Ok(Ok(completed_process)),
+        Err(subprocess_error) => Ok(Err(ExpressionResult::Value(
+            subprocess_error_to_expression(subprocess_error),
+        ))),
     }
+}
 
-    #[test]
-    fn test_eval_builtin_function_not_exists() {
-        let env = create_test_env();
-        let args = vec![];
+/// Describe a failed run as a map with its `message`, and the `returncode`,
+/// `stdout` and `stderr` of the command when it ran (None when it could not start)
+fn subprocess_error_to_record(subprocess_error: SubprocessError) -> Expression {
+    let optional_string = |value: Option<String>| value.map_or(Expression::CVoid, Expression::CString);
+    let message = subprocess_error.to_string();
+    let (returncode, stdout, stderr) = match subprocess_error {
+        SubprocessError::CalledProcessError { returncode, stdout, stderr, .. } => {
+            (Expression::CInt(returncode_value(returncode)), optional_string(stdout), optional_string(stderr))
+        }
+        _ => (Expression::CVoid, Expression::CVoid, Expression::CVoid),
+    };
+    Expression::MapValue(vec![
+        (Expression::CString("message".to_string()), Expression::CString(message)),
+        (Expression::CString("returncode".to_string()), returncode),
+        (Expression::CString("stdout".to_string()), stdout),
+        (Expression::CString("stderr".to_string()), stderr),
+    ])
+}
 
-        let result = eval_builtin_function(&"nonexistent.function".to_string(), args, &env);
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+/// Implementation of subprocess.run_result built-in function.
+/// Captures output and returns Ok(CompletedProcess) when the command exits with
+/// one of the `success_codes` (by default only 0), or Err(record) when it exits non-zero or cannot be started, where the
+/// record is a map with `message`, `returncode`, `stdout` and `stderr`.
+/// Works with `unwrap`, `isError` and `?` like any other Result.
+fn subprocess_run_result_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("subprocess.run_result() takes 1 to 2 arguments".to_string());
     }
 
-    #[test]
-    fn test_subprocess_run_basic_command() {
-        let env = create_test_env();
-        let args = vec![
-            Expression::ListValue(vec![
-                Expression::CString("echo".to_string()),
-                Expression::CString("hello".to_string()),
-            ]),
-            Expression::CFalse, // shell=False
-            Expression::CTrue,  // capture_output=True
-        ];
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
 
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_ok());
-        
-        match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
-                assert_eq!(returncode, 0);
-                assert!(stdout.is_some());
-                assert!(stderr.is_some());
-                assert!(stdout.unwrap().contains("hello"));
-            }
-            _ => panic!("Expected CompletedProcess result"),
-        }
+    let mut options = RunOptions {
+        capture_output: true,
+        check: true,
+        ..default_run_options(env)
+    };
+    // Second argument: shell (optional, default False)
+    if evaluated_args.len() > 1 {
+        options.shell = parse_bool_argument(&evaluated_args[1], "subprocess.run_result", "shell")?;
     }
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.run_result", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
 
-    #[test]
-    fn test_subprocess_run_shell_command() {
-        let env = create_test_env();
-        let args = vec![
-            Expression::CString("echo shell_test".to_string()),
-            Expression::CTrue,  // shell=True
-            Expression::CTrue,  // capture_output=True
-        ];
-
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_ok());
-        
-        match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
-                assert_eq!(returncode, 0);
-                assert!(stdout.is_some());
-                assert!(stderr.is_some());
-                assert!(stdout.unwrap().contains("shell_test"));
-            }
-            _ => panic!("Expected CompletedProcess result"),
-        }
+    match execute_command(command, options, env) {
+        Ok(completed_process) => Ok(ExpressionResult::Value(Expression::COk(Box::new(
+            completed_process_to_expression(completed_process),
+        )))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+            subprocess_error_to_record(subprocess_error),
+        )))),
     }
+}
 
-    #[test]
-    fn test_subprocess_run_invalid_arguments() {
-        let env = create_test_env();
-        
-        // Test with no arguments
-        let result = subprocess_run_builtin(vec![], &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+/// Implementation of subprocess.ok built-in function.
+/// Runs the command without capturing output and returns whether it exited with
+/// code 0, or another of the environment's `success_codes`.
+fn subprocess_ok_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = default_run_options(env);
+    let completed_process = match run_builtin_command(args, env, "subprocess.ok", options.clone())? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
 
-        // Test with too many arguments
-        let result = subprocess_run_builtin(vec![
-            Expression::CString("echo".to_string()),
-            Expression::CFalse,
-            Expression::CFalse,
-            Expression::CFalse, // 4th argument - too many
-        ], &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+    if options.is_success(completed_process.returncode) {
+        Ok(ExpressionResult::Value(Expression::CTrue))
+    } else {
+        Ok(ExpressionResult::Value(Expression::CFalse))
     }
+}
 
-    #[test]
-    fn test_subprocess_run_invalid_command_type() {
-        let env = create_test_env();
-        let args = vec![
-            Expression::CInt(42), // Invalid command type
-        ];
-
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("must be a list of strings or a string"));
+/// Implementation of subprocess.expect_code built-in function.
+/// Runs the command without capturing output and returns whether it exited
+/// with exactly the expected code.
+fn subprocess_expect_code_builtin(
+    mut args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.expect_code() takes exactly 2 arguments".to_string());
     }
 
-    #[test]
-    fn test_subprocess_run_invalid_shell_argument() {
-        let env = create_test_env();
-        let args = vec![
-            Expression::CString("echo test".to_string()),
-            Expression::CInt(1), // Invalid shell argument type
-        ];
+    let expected_arg = match evaluate_arguments(args.split_off(1), env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+    let expected = match &expected_arg[0] {
+        Expression::CInt(code) => *code,
+        _ => return Err("subprocess.expect_code() expected code must be an integer".to_string()),
+    };
 
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("shell argument must be a boolean"));
+    let options = default_run_options(env);
+    let completed_process = match run_builtin_command(args, env, "subprocess.expect_code", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    if completed_process.returncode == Some(expected) {
+        Ok(ExpressionResult::Value(Expression::CTrue))
+    } else {
+        Ok(ExpressionResult::Value(Expression::CFalse))
     }
+}
 
-    #[test]
-    fn test_subprocess_run_invalid_capture_output_argument() {
+/// Implementation of subprocess.run_strict built-in function.
+/// Captures output and returns the CompletedProcess only if the command finishes
+/// within `timeout_ms` milliseconds with exit code 0. Otherwise returns a CErr
+/// that says whether it timed out or failed.
+fn subprocess_run_strict_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.run_strict() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let timeout_ms = match &evaluated_args[1] {
+        Expression::CInt(ms) if *ms >= 0 => *ms as u64,
+        _ => {
+            return Err("subprocess.run_strict() timeout_ms must be a non-negative integer".to_string())
+        }
+    };
+
+    let mut options = RunOptions {
+        capture_output: true,
+        timeout: Some(std::time::Duration::from_millis(timeout_ms)),
+        ..default_run_options(env)
+    };
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.run_strict", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
+
+    let program = command[0].clone();
+    match execute_command(command, options, env) {
+        Ok(completed_process) if completed_process.returncode == Some(0) => Ok(ExpressionResult::Value(
+            completed_process_to_expression(completed_process),
+        )),
+        Ok(completed_process) => Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+            Expression::CString(format!(
+                "Command '{}' returned non-zero exit status {}",
+                program, returncode_value(completed_process.returncode)
+            )),
+        )))),
+        // Timeouts keep their own message, so they can be told apart from failures
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.run_int built-in function.
+/// Captures stdout and parses it (trimmed) as an integer.
+fn subprocess_run_int_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_int", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let text = stdout.trim();
+    let value = text
+        .parse::<i64>()
+        .ok()
+        .and_then(|value| i32::try_from(value).ok());
+
+    match value {
+        Some(value) => Ok(ExpressionResult::Value(Expression::CInt(value))),
+        None => Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+            Expression::CString(format!(
+                "subprocess.run_int() could not parse '{}' as an integer",
+                text
+            )),
+        )))),
+    }
+}
+
+/// Implementation of subprocess.run_bool built-in function.
+/// Captures stdout and maps it (trimmed, ignoring case) to a boolean:
+/// true/yes/y/on/1 give True and false/no/n/off/0 give False.
+fn subprocess_run_bool_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_bool", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let text = stdout.trim();
+    match text.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "y" | "on" | "1" => Ok(ExpressionResult::Value(Expression::CTrue)),
+        "false" | "no" | "n" | "off" | "0" => Ok(ExpressionResult::Value(Expression::CFalse)),
+        _ => Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+            Expression::CString(format!(
+                "subprocess.run_bool() could not interpret '{}' as a boolean",
+                text
+            )),
+        )))),
+    }
+}
+
+/// Implementation of subprocess.run_ints built-in function.
+/// Captures stdout, splits it on whitespace and parses every token as an
+/// integer. Fails on the first token that is not one, reporting its position
+/// (counting from 0).
+fn subprocess_run_ints_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_ints", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let mut values = Vec::new();
+    for (index, token) in stdout.split_whitespace().enumerate() {
+        let value = token.parse::<i64>().ok().and_then(|value| i32::try_from(value).ok());
+        match value {
+            Some(value) => values.push(Expression::CInt(value)),
+            None => {
+                return Ok(ExpressionResult::Value(Expression::CErr(Box::new(Expression::CString(
+                    format!(
+                        "subprocess.run_ints() could not parse '{}' at position {} as an integer",
+                        token, index
+                    ),
+                )))))
+            }
+        }
+    }
+
+    Ok(ExpressionResult::Value(Expression::ListValue(values)))
+}
+
+/// Implementation of subprocess.run_bytes built-in function.
+/// Captures stdout without decoding it and returns the raw bytes.
+fn subprocess_run_bytes_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        text: false,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_bytes", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    Ok(ExpressionResult::Value(Expression::CBytes(
+        completed_process.stdout_bytes.unwrap_or_default(),
+    )))
+}
+
+/// Implementation of subprocess.run_outlen built-in function.
+/// Returns the number of bytes the command wrote to stdout, counting them as
+/// they arrive instead of capturing the output.
+fn subprocess_run_outlen_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.run_outlen() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+    if options.shell {
+        return Err("subprocess.run_outlen() cannot run shell commands".to_string());
+    }
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.run_outlen", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
+
+    match count_command_output(command, options, env) {
+        Ok(length) => match i32::try_from(length) {
+            Ok(length) => Ok(ExpressionResult::Value(Expression::CInt(length))),
+            Err(_) => Ok(ExpressionResult::Value(Expression::CErr(Box::new(Expression::CString(
+                format!("output length {} does not fit in an integer", length),
+            ))))),
+        },
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.run_throughput built-in function.
+/// Counts the bytes the command writes to stdout, without decoding or keeping
+/// them, and times the run. Returns a map with `bytes` and `elapsed_ms`, from
+/// which the caller can compute the throughput.
+fn subprocess_run_throughput_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.run_throughput() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+    if options.shell {
+        return Err("subprocess.run_throughput() cannot run shell commands".to_string());
+    }
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.run_throughput", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
+
+    let start = std::time::Instant::now();
+    let bytes = match count_command_output(command, options, env) {
+        Ok(bytes) => bytes,
+        Err(subprocess_error) => {
+            return Ok(ExpressionResult::Value(subprocess_error_to_expression(subprocess_error)))
+        }
+    };
+    let elapsed_ms = start.elapsed().as_millis();
+
+    Ok(ExpressionResult::Value(Expression::MapValue(vec![
+        (
+            Expression::CString("bytes".to_string()),
+            Expression::CInt(i32::try_from(bytes).unwrap_or(i32::MAX)),
+        ),
+        (
+            Expression::CString("elapsed_ms".to_string()),
+            Expression::CInt(i32::try_from(elapsed_ms).unwrap_or(i32::MAX)),
+        ),
+    ])))
+}
+
+/// Implementation of subprocess.run_progress built-in function.
+/// Captures the output of a command and counts its stdout lines; a last line
+/// without a trailing newline counts too. Returns a map with `stdout`,
+/// `returncode` and the total number of `lines`. Embedders wanting progress
+/// while the command runs can install an output sink (`set_output_sink`),
+/// which receives every line as it arrives.
+fn subprocess_run_progress_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_progress", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let lines = stdout.lines().count();
+    Ok(ExpressionResult::Value(Expression::MapValue(vec![
+        (
+            Expression::CString("returncode".to_string()),
+            Expression::CInt(returncode_value(completed_process.returncode)),
+        ),
+        (
+            Expression::CString("lines".to_string()),
+            Expression::CInt(i32::try_from(lines).unwrap_or(i32::MAX)),
+        ),
+        (Expression::CString("stdout".to_string()), Expression::CString(stdout)),
+    ])))
+}
+
+/// Implementation of subprocess.run_base64 built-in function.
+/// Captures stdout as raw bytes and returns it base64-encoded, so binary output
+/// can be passed around as a string.
+fn subprocess_run_base64_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        text: false,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_base64", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout_bytes.unwrap_or_default();
+    Ok(ExpressionResult::Value(Expression::CString(
+        base64::engine::general_purpose::STANDARD.encode(stdout),
+    )))
+}
+
+/// Implementation of subprocess.run_sha256 built-in function.
+/// Captures stdout as raw bytes and returns its SHA-256 digest as lowercase hex.
+fn subprocess_run_sha256_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        text: false,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_sha256", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let digest = Sha256::digest(completed_process.stdout_bytes.unwrap_or_default());
+    let hex = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(ExpressionResult::Value(Expression::CString(hex)))
+}
+
+/// Implementation of subprocess.run_rusage built-in function.
+/// Runs the command capturing output and returns a map with `returncode`,
+/// `stdout`, `stderr` and, on Unix, the child's `max_rss_kb`, `user_ms` and `sys_ms`.
+fn subprocess_run_rusage_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        rusage: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_rusage", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let entry = |key: &str, value: Expression| (Expression::CString(key.to_string()), value);
+    let optional_string = |value: Option<String>| match value {
+        Some(text) => Expression::CString(text),
+        None => Expression::CVoid,
+    };
+    let mut entries = vec![
+        entry("returncode", Expression::CInt(returncode_value(completed_process.returncode))),
+        entry("stdout", optional_string(completed_process.stdout)),
+        entry("stderr", optional_string(completed_process.stderr)),
+    ];
+
+    // Resource usage is only collected on Unix; elsewhere the fields are absent
+    if let Some(rusage) = completed_process.rusage {
+        let int = |value: i64| Expression::CInt(i32::try_from(value).unwrap_or(i32::MAX));
+        entries.push(entry("max_rss_kb", int(rusage.max_rss_kb)));
+        entries.push(entry("user_ms", int(rusage.user_ms)));
+        entries.push(entry("sys_ms", int(rusage.sys_ms)));
+    }
+
+    Ok(ExpressionResult::Value(Expression::MapValue(entries)))
+}
+
+/// Implementation of subprocess.run_cputime built-in function.
+/// Runs the command capturing output and returns a map with `returncode`,
+/// `stdout` and `cpu_ms`, the user plus system CPU time of the child in
+/// milliseconds. Comparing it with wall time tells CPU-bound commands from
+/// IO-bound ones. CPU time is only collected on Unix; elsewhere `cpu_ms` is -1.
+fn subprocess_run_cputime_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        rusage: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_cputime", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let cpu_ms = completed_process.rusage.map_or(-1, |rusage| {
+        i32::try_from(rusage.user_ms + rusage.sys_ms).unwrap_or(i32::MAX)
+    });
+    let stdout = completed_process.stdout.map_or(Expression::CVoid, Expression::CString);
+    Ok(ExpressionResult::Value(Expression::MapValue(vec![
+        (
+            Expression::CString("returncode".to_string()),
+            Expression::CInt(returncode_value(completed_process.returncode)),
+        ),
+        (Expression::CString("stdout".to_string()), stdout),
+        (Expression::CString("cpu_ms".to_string()), Expression::CInt(cpu_ms)),
+    ])))
+}
+
+/// Implementation of subprocess.run_pty built-in function.
+/// Runs the command attached to a pseudo-terminal (Unix only) and returns a
+/// CompletedProcess whose stdout holds everything written to the terminal.
+fn subprocess_run_pty_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        pty: true,
+        ..default_run_options(env)
+    };
+    match run_builtin_command(args, env, "subprocess.run_pty", options)? {
+        Ok(completed_process) => Ok(ExpressionResult::Value(completed_process_to_expression(
+            completed_process,
+        ))),
+        Err(early_return) => Ok(early_return),
+    }
+}
+
+/// Implementation of subprocess.run_template built-in function.
+/// Builds the argv from a template such as `"grep {} file"`, putting each value
+/// into the argument holding its `{}` placeholder, then runs it without a shell
+/// and captures output. Values can't inject arguments or shell syntax.
+fn subprocess_run_template_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.run_template() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let template = match &evaluated_args[0] {
+        Expression::CString(template) => template,
+        _ => return Err("subprocess.run_template() template must be a string".to_string()),
+    };
+    let values = match &evaluated_args[1] {
+        Expression::ListValue(items) => items
+            .iter()
+            .map(|item| match item {
+                Expression::CString(value) => Ok(value.clone()),
+                other => Err(format!(
+                    "subprocess.run_template() values must be strings, got {}",
+                    variant_name(other)
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()?,
+        _ => return Err("subprocess.run_template() values must be a list".to_string()),
+    };
+
+    // The filled argv is run as is, whatever the environment's default shell mode
+    let mut options = RunOptions {
+        capture_output: true,
+        shell: false,
+        ..default_run_options(env)
+    };
+    apply_environment_options(env, &mut options);
+
+    let result = crate::stdlib::shlex::fill_template(template, &values)
+        .and_then(|command| execute_command(command, options, env));
+    match result {
+        Ok(completed_process) => {
+            Ok(ExpressionResult::Value(completed_process_to_expression(completed_process)))
+        }
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.run_with_env built-in function.
+/// `subprocess.run_with_env(name, value, cmd[, shell])` runs the command with
+/// one environment variable set, on top of the inherited environment, and
+/// captures output. Shorthand for the `env` option with a single pair.
+fn subprocess_run_with_env_builtin(
+    mut args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() < 3 || args.len() > 4 {
+        return Err("subprocess.run_with_env() takes 3 to 4 arguments".to_string());
+    }
+
+    let command_args = args.split_off(2);
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+    let (name, value) = match (&evaluated_args[0], &evaluated_args[1]) {
+        (Expression::CString(name), Expression::CString(value)) => (name.clone(), value.clone()),
+        _ => return Err("subprocess.run_with_env() name and value must be strings".to_string()),
+    };
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(format!("subprocess.run_with_env() invalid variable name '{}'", name));
+    }
+
+    let mut options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    options.env.push((name, value));
+    match run_builtin_command(command_args, env, "subprocess.run_with_env", options)? {
+        Ok(completed_process) => Ok(ExpressionResult::Value(completed_process_to_expression(
+            completed_process,
+        ))),
+        Err(early_return) => Ok(early_return),
+    }
+}
+
+/// Implementation of subprocess.run_words built-in function.
+/// Captures stdout and splits it on any whitespace, like `$(cmd)` word splitting.
+fn subprocess_run_words_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_words", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let words = completed_process
+        .stdout
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|word| Expression::CString(word.to_string()))
+        .collect();
+
+    Ok(ExpressionResult::Value(Expression::ListValue(words)))
+}
+
+/// Implementation of subprocess.run_firstline built-in function.
+/// Captures stdout and returns its first line without the line ending,
+/// or an empty string when there is no output (e.g. a version string).
+fn subprocess_run_firstline_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_firstline", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let first_line = stdout.lines().next().unwrap_or_default();
+
+    Ok(ExpressionResult::Value(Expression::CString(first_line.to_string())))
+}
+
+/// Implementation of subprocess.run_combined built-in function.
+/// Captures stdout and stderr through one pipe and returns them as a single
+/// string, interleaved in the order the command wrote them.
+fn subprocess_run_combined_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        merge_stderr: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_combined", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    Ok(ExpressionResult::Value(Expression::CString(
+        completed_process.stdout.unwrap_or_default(),
+    )))
+}
+
+/// Implementation of subprocess.run_to_tmpfile built-in function.
+/// Redirects stdout straight into a new temporary file, so large output is
+/// never held in memory, and returns the file's path. The file is kept after
+/// the call; removing it is up to the caller.
+fn subprocess_run_to_tmpfile_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let (_, path) = tempfile::Builder::new()
+        .prefix("r-python-")
+        .tempfile()
+        .and_then(|file| file.keep().map_err(|error| error.error))
+        .map_err(|error| format!("subprocess.run_to_tmpfile() could not create a temporary file: {}", error))?;
+    let path_string = path.to_string_lossy().into_owned();
+
+    // Only stdout is redirected; stderr goes wherever the defaults send it
+    let options = RunOptions {
+        stdout: Some(StdioTarget::File(path_string.clone())),
+        ..default_run_options(env)
+    };
+    let result = run_builtin_command(args, env, "subprocess.run_to_tmpfile", options);
+    if !matches!(result, Ok(Ok(_))) {
+        // The command failed, so don't leave its file behind
+        let _ = std::fs::remove_file(&path);
+    }
+    match result? {
+        Ok(_) => Ok(ExpressionResult::Value(Expression::CString(path_string))),
+        Err(early_return) => Ok(early_return),
+    }
+}
+
+/// Implementation of subprocess.run_map built-in function.
+/// Captures output and returns the result as a map with the keys `returncode`,
+/// `stdout` and `stderr`; a stream that was not captured is None.
+fn subprocess_run_map_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_map", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stream = |value: Option<String>| value.map_or(Expression::CVoid, Expression::CString);
+    Ok(ExpressionResult::Value(Expression::MapValue(vec![
+        (
+            Expression::CString("returncode".to_string()),
+            Expression::CInt(returncode_value(completed_process.returncode)),
+        ),
+        (Expression::CString("stdout".to_string()), stream(completed_process.stdout)),
+        (Expression::CString("stderr".to_string()), stream(completed_process.stderr)),
+    ])))
+}
+
+/// Implementation of subprocess.run_kv built-in function.
+/// Captures stdout and parses `key=value` lines into a map, splitting on the first `=`.
+/// Lines without `=` are skipped, or reported as an error when `strict` is true.
+fn subprocess_run_kv_builtin(
+    mut args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() > 3 {
+        return Err("subprocess.run_kv() takes 1 to 3 arguments".to_string());
+    }
+
+    // Third argument: strict (optional, default False)
+    let mut strict = false;
+    if args.len() == 3 {
+        let strict_arg = match evaluate_arguments(args.split_off(2), env)? {
+            Ok(values) => values,
+            Err(propagated) => return Ok(propagated),
+        };
+        strict = parse_bool_argument(&strict_arg[0], "subprocess.run_kv", "strict")?;
+    }
+
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_kv", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let mut entries: Vec<(Expression, Expression)> = Vec::new();
+    for line in completed_process.stdout.unwrap_or_default().lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(pair) => pair,
+            None if strict => {
+                return Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+                    Expression::CString(format!(
+                        "subprocess.run_kv() line '{}' is not a key=value pair",
+                        line
+                    )),
+                ))));
+            }
+            None => continue,
+        };
+
+        // A repeated key keeps its first position but takes the last value
+        let key = Expression::CString(key.to_string());
+        let value = Expression::CString(value.to_string());
+        match entries.iter_mut().find(|(existing, _)| *existing == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key, value)),
+        }
+    }
+
+    Ok(ExpressionResult::Value(Expression::MapValue(entries)))
+}
+
+/// Implementation of subprocess.run_csv built-in function.
+/// Captures stdout and parses it as CSV into a list of rows, each a list of
+/// strings. There is no header handling and rows may differ in length.
+/// The delimiter defaults to `,` and must be a single ASCII character.
+fn subprocess_run_csv_builtin(
+    mut args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() > 3 {
+        return Err("subprocess.run_csv() takes 1 to 3 arguments".to_string());
+    }
+
+    // Third argument: delimiter (optional, default ",")
+    let mut delimiter = b',';
+    if args.len() == 3 {
+        let delimiter_arg = match evaluate_arguments(args.split_off(2), env)? {
+            Ok(values) => values,
+            Err(propagated) => return Ok(propagated),
+        };
+        delimiter = match &delimiter_arg[0] {
+            Expression::CString(text) if text.len() == 1 && text.is_ascii() => text.as_bytes()[0],
+            _ => {
+                return Err(
+                    "subprocess.run_csv() delimiter argument must be a single ASCII character".to_string(),
+                )
+            }
+        };
+    }
+
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_csv", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let stdout = completed_process.stdout.unwrap_or_default();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(stdout.as_bytes());
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        match record {
+            Ok(record) => rows.push(Expression::ListValue(
+                record.iter().map(|field| Expression::CString(field.to_string())).collect(),
+            )),
+            Err(e) => {
+                return Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+                    Expression::CString(format!("subprocess.run_csv() invalid CSV output: {}", e)),
+                ))));
+            }
+        }
+    }
+
+    Ok(ExpressionResult::Value(Expression::ListValue(rows)))
+}
+
+/// Convert a parsed JSON value into an RPython value. Integers that fit in
+/// 32 bits become CInt, other numbers CReal, and `null` becomes CVoid.
+fn json_to_expression(value: serde_json::Value) -> Expression {
+    match value {
+        serde_json::Value::Null => Expression::CVoid,
+        serde_json::Value::Bool(true) => Expression::CTrue,
+        serde_json::Value::Bool(false) => Expression::CFalse,
+        serde_json::Value::Number(number) => match number.as_i64().and_then(|n| i32::try_from(n).ok()) {
+            Some(n) => Expression::CInt(n),
+            None => Expression::CReal(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(text) => Expression::CString(text),
+        serde_json::Value::Array(items) => {
+            Expression::ListValue(items.into_iter().map(json_to_expression).collect())
+        }
+        serde_json::Value::Object(fields) => Expression::MapValue(
+            fields
+                .into_iter()
+                .map(|(key, value)| (Expression::CString(key), json_to_expression(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Implementation of subprocess.run_jsonl built-in function.
+/// Captures stdout and parses each non-blank line as JSON, returning a list of values.
+fn subprocess_run_jsonl_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_jsonl", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let mut values = Vec::new();
+    for (index, line) in completed_process.stdout.unwrap_or_default().lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(value) => values.push(json_to_expression(value)),
+            Err(e) => {
+                return Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+                    Expression::CString(format!(
+                        "subprocess.run_jsonl() invalid JSON on line {}: {}",
+                        index + 1,
+                        e
+                    )),
+                ))));
+            }
+        }
+    }
+
+    Ok(ExpressionResult::Value(Expression::ListValue(values)))
+}
+
+/// Implementation of subprocess.run_json_raw built-in function.
+/// Captures stdout and returns a map with the parsed `json` (None when stdout
+/// isn't valid JSON), the `raw` stdout, the parse `error` (None on success) and
+/// the `returncode`, so a script can fall back to the raw text.
+fn subprocess_run_json_raw_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    let completed_process = match run_builtin_command(args, env, "subprocess.run_json_raw", options)? {
+        Ok(completed_process) => completed_process,
+        Err(early_return) => return Ok(early_return),
+    };
+
+    let raw = completed_process.stdout.unwrap_or_default();
+    let (json, error) = match serde_json::from_str(&raw) {
+        Ok(value) => (json_to_expression(value), Expression::CVoid),
+        Err(e) => (Expression::CVoid, Expression::CString(e.to_string())),
+    };
+    let entry = |key: &str, value: Expression| (Expression::CString(key.to_string()), value);
+    Ok(ExpressionResult::Value(Expression::MapValue(vec![
+        entry("json", json),
+        entry("raw", Expression::CString(raw)),
+        entry("error", error),
+        entry("returncode", Expression::CInt(returncode_value(completed_process.returncode))),
+    ])))
+}
+
+/// Exit code as RPython programs see it: -1 for a process that did not exit
+/// normally, whose `signal` then says what terminated it
+fn returncode_value(returncode: Option<i32>) -> i32 {
+    returncode.unwrap_or(-1)
+}
+
+/// Convert a CompletedProcess into its RPython value
+fn completed_process_to_expression(completed_process: CompletedProcess) -> Expression {
+    Expression::CompletedProcess {
+        args: completed_process.args,
+        returncode: returncode_value(completed_process.returncode),
+        stdout: completed_process.stdout,
+        stderr: completed_process.stderr,
+        signal: completed_process.signal,
+        stdout_lossy: completed_process.stdout_lossy,
+        stderr_lossy: completed_process.stderr_lossy,
+    }
+}
+
+/// Describe where an output stream goes, as reported by subprocess.last_options
+fn stdio_target_to_expression(target: StdioTarget) -> Expression {
+    match target {
+        StdioTarget::Inherit => Expression::CString("inherit".to_string()),
+        StdioTarget::Pipe => Expression::CString("pipe".to_string()),
+        StdioTarget::Null => Expression::CString("null".to_string()),
+        StdioTarget::File(path) => Expression::CString(format!("file:{}", path)),
+    }
+}
+
+/// Convert run options into a map describing them, keyed by option name.
+/// Unset optional values are None.
+fn run_options_to_expression(options: &RunOptions) -> Expression {
+    let bool_value = |value: bool| if value { Expression::CTrue } else { Expression::CFalse };
+    let optional_string = |value: &Option<String>| match value {
+        Some(value) => Expression::CString(value.clone()),
+        None => Expression::CVoid,
+    };
+    let timeout_ms = match options.timeout {
+        Some(timeout) => Expression::CInt(i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX)),
+        None => Expression::CVoid,
+    };
+    let env_overrides = options
+        .env
+        .iter()
+        .map(|(key, value)| (Expression::CString(key.clone()), Expression::CString(value.clone())))
+        .collect();
+
+    let entries = vec![
+        ("shell", bool_value(options.shell)),
+        ("split_string", bool_value(options.split_string)),
+        ("capture_output", bool_value(options.capture_output)),
+        ("stdin", stdio_target_to_expression(options.stdin_target())),
+        ("stdout", stdio_target_to_expression(options.stdout_target())),
+        ("stderr", stdio_target_to_expression(options.stderr_target())),
+        ("merge_stderr", bool_value(options.merge_stderr)),
+        ("text", bool_value(options.text)),
+        ("strict", bool_value(options.strict)),
+        ("strip", bool_value(options.strip)),
+        ("strip_ansi", bool_value(options.strip_ansi)),
+        ("check", bool_value(options.check)),
+        (
+            "success_codes",
+            Expression::ListValue(options.success_codes.iter().map(|code| Expression::CInt(*code)).collect()),
+        ),
+        ("timeout_ms", timeout_ms),
+        ("cwd", optional_string(&options.cwd)),
+        ("label", optional_string(&options.label)),
+        ("env", Expression::MapValue(env_overrides)),
+        ("input", bool_value(options.input.is_some())),
+        ("pty", bool_value(options.pty)),
+    ];
+    Expression::MapValue(
+        entries
+            .into_iter()
+            .map(|(name, value)| (Expression::CString(name.to_string()), value))
+            .collect(),
+    )
+}
+
+/// Implementation of subprocess.last_options built-in function.
+/// Returns a map describing the effective options of the most recent subprocess
+/// call made from this environment, or None if there was none. Useful to see
+/// why `stdout` or `stderr` came back as None: only piped streams are captured.
+fn subprocess_last_options_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if !args.is_empty() {
+        return Err("subprocess.last_options() takes no arguments".to_string());
+    }
+
+    let context = env.subprocess.borrow();
+    match context.last_options() {
+        Some(options) => Ok(ExpressionResult::Value(run_options_to_expression(options))),
+        None => Ok(ExpressionResult::Value(Expression::CVoid)),
+    }
+}
+
+/// Implementation of subprocess.map built-in function.
+/// Runs a list of commands concurrently, capturing output, with at most
+/// `max_parallel` running at once. Returns the results in input order.
+fn subprocess_map_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.map() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let commands = match &evaluated_args[0] {
+        Expression::ListValue(items) => items
+            .iter()
+            .map(|item| parse_command_argument(item, "subprocess.map", true))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => return Err("subprocess.map() first argument must be a list of commands".to_string()),
+    };
+
+    let max_parallel = match &evaluated_args[1] {
+        Expression::CInt(n) if *n >= 1 => *n as usize,
+        _ => return Err("subprocess.map() max_parallel must be a positive integer".to_string()),
+    };
+
+    let mut options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    apply_environment_options(env, &mut options);
+
+    let results = run_commands_parallel(commands, options, max_parallel)
+        .into_iter()
+        .map(|result| match result {
+            Ok(completed_process) => completed_process_to_expression(completed_process),
+            Err(subprocess_error) => subprocess_error_to_expression(subprocess_error),
+        })
+        .collect();
+
+    Ok(ExpressionResult::Value(Expression::ListValue(results)))
+}
+
+/// Implementation of subprocess.pipeline built-in function.
+/// Runs a list of commands with each one's stdout piped into the next, and
+/// captures the output of the last. The optional `timeout_ms` bounds the whole
+/// pipeline; when it passes every stage is killed.
+fn subprocess_pipeline_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("subprocess.pipeline() takes 1 to 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let commands = match &evaluated_args[0] {
+        Expression::ListValue(items) => items
+            .iter()
+            .map(|item| parse_command_argument(item, "subprocess.pipeline", true))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => return Err("subprocess.pipeline() first argument must be a list of commands".to_string()),
+    };
+
+    let mut options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    if evaluated_args.len() > 1 {
+        match &evaluated_args[1] {
+            Expression::CInt(ms) if *ms >= 0 => {
+                options.timeout = Some(std::time::Duration::from_millis(*ms as u64))
+            }
+            _ => {
+                return Err("subprocess.pipeline() timeout_ms must be a non-negative integer".to_string())
+            }
+        }
+    }
+    apply_environment_options(env, &mut options);
+
+    match run_pipeline(commands, options) {
+        Ok(completed_process) => {
+            Ok(ExpressionResult::Value(completed_process_to_expression(completed_process)))
+        }
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.spawn_detached built-in function.
+/// Starts the command in the background, detached from the interpreter, and
+/// returns its PID. The process keeps running after the interpreter exits.
+fn subprocess_spawn_detached_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.spawn_detached() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.spawn_detached", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
+
+    match spawn_detached(command, options) {
+        Ok(pid) => Ok(ExpressionResult::Value(Expression::CInt(pid as i32))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.kill_pid built-in function.
+/// Sends a signal, given by number or name (e.g. 15 or "SIGTERM"), to a process
+/// by PID. Returns a CErr if the process doesn't exist. On Windows the process
+/// is always terminated, whatever the signal.
+fn subprocess_kill_pid_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.kill_pid() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let pid = match &evaluated_args[0] {
+        Expression::CInt(pid) => *pid,
+        _ => return Err("subprocess.kill_pid() pid must be an integer".to_string()),
+    };
+
+    #[cfg(not(windows))]
+    let result = {
+        use nix::sys::signal::Signal;
+        use std::str::FromStr;
+
+        let signal = match &evaluated_args[1] {
+            Expression::CInt(number) => Signal::try_from(*number).ok(),
+            Expression::CString(name) => Signal::from_str(name).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| "subprocess.kill_pid() signal must be a signal number or name".to_string())?;
+        kill_pid(pid, signal)
+    };
+    #[cfg(windows)]
+    let result = kill_pid(pid);
+
+    match result {
+        Ok(()) => Ok(ExpressionResult::Value(Expression::CVoid)),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.pkill built-in function.
+/// Sends SIGTERM to every process running a program with the given name and
+/// returns how many were signaled. On Windows the processes are terminated.
+fn subprocess_pkill_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.pkill() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let name = match &evaluated_args[0] {
+        Expression::CString(name) => name,
+        _ => return Err("subprocess.pkill() name must be a string".to_string()),
+    };
+
+    match pkill(name) {
+        Ok(killed) => Ok(ExpressionResult::Value(Expression::CInt(
+            i32::try_from(killed).unwrap_or(i32::MAX),
+        ))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.wait_pid built-in function.
+/// Blocks until the process with the given PID exits and returns its exit code.
+/// Processes that aren't children of the interpreter can only be polled, so
+/// their exit code is reported as -1.
+fn subprocess_wait_pid_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.wait_pid() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let pid = match &evaluated_args[0] {
+        Expression::CInt(pid) => *pid,
+        _ => return Err("subprocess.wait_pid() pid must be an integer".to_string()),
+    };
+
+    match wait_pid(pid) {
+        Ok(returncode) => Ok(ExpressionResult::Value(Expression::CInt(returncode_value(returncode)))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.explain_code built-in function.
+/// Returns a human-readable meaning of an exit code, e.g. 127 is "command not found".
+fn subprocess_explain_code_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.explain_code() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CInt(code) => Ok(ExpressionResult::Value(Expression::CString(
+            explain_exit_code(*code),
+        ))),
+        _ => Err("subprocess.explain_code() code must be an integer".to_string()),
+    }
+}
+
+/// Implementation of subprocess.equal built-in function.
+/// Compares two CompletedProcess values by returncode, stdout, stderr and signal.
+fn subprocess_equal_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.equal() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    // The command line and decoding flags are not part of the comparison
+    let mut outcomes = Vec::new();
+    for (position, value) in ["first", "second"].iter().zip(&evaluated_args) {
+        match value {
+            Expression::CompletedProcess { returncode, stdout, stderr, signal, .. } => {
+                outcomes.push((returncode, stdout, stderr, signal))
+            }
+            _ => {
+                return Err(format!(
+                    "subprocess.equal() {} argument must be a CompletedProcess, got {}",
+                    position,
+                    variant_name(value)
+                ))
+            }
+        }
+    }
+
+    Ok(ExpressionResult::Value(if outcomes[0] == outcomes[1] {
+        Expression::CTrue
+    } else {
+        Expression::CFalse
+    }))
+}
+
+/// Implementation of subprocess.diff built-in function.
+/// Runs two commands capturing their output and returns a unified diff from
+/// the stdout of the first to that of the second; empty when they are identical.
+fn subprocess_diff_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.diff() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = RunOptions {
+        capture_output: true,
+        ..default_run_options(env)
+    };
+    apply_environment_options(env, &mut options);
+
+    let mut outputs = Vec::new();
+    for value in &evaluated_args {
+        let command = parse_command_argument(value, "subprocess.diff", options.splits_command_string())?;
+        let header = command.join(" ");
+        match execute_command(command, options.clone(), env) {
+            Ok(completed_process) => outputs.push((header, completed_process.stdout.unwrap_or_default())),
+            Err(subprocess_error) => {
+                return Ok(ExpressionResult::Value(subprocess_error_to_expression(subprocess_error)))
+            }
+        }
+    }
+
+    let (header_a, stdout_a) = &outputs[0];
+    let (header_b, stdout_b) = &outputs[1];
+    let diff = similar::TextDiff::from_lines(stdout_a, stdout_b)
+        .unified_diff()
+        .header(header_a, header_b)
+        .to_string();
+    Ok(ExpressionResult::Value(Expression::CString(diff)))
+}
+
+/// Implementation of subprocess.resolve built-in function.
+/// Returns the absolute path of the executable the command's first element
+/// would run, searching the `PATH` the child would see, without running it.
+fn subprocess_resolve_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.resolve() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+    apply_environment_options(env, &mut options);
+    let command = parse_command_argument(&evaluated_args[0], "subprocess.resolve", options.splits_command_string())?;
+    let program = command
+        .first()
+        .ok_or_else(|| "subprocess.resolve() command cannot be empty".to_string())?;
+
+    // Resolved exactly as spawning the command resolves it
+    let search_path = options.child_env_var("PATH").unwrap_or_default();
+    let pathext = options.child_env_var("PATHEXT");
+    let resolved = crate::stdlib::shutil::which_in(
+        program,
+        std::ffi::OsStr::new(&search_path),
+        pathext.as_deref().map(std::ffi::OsStr::new),
+    )
+    .and_then(|path| std::path::absolute(path).ok());
+    Ok(ExpressionResult::Value(match resolved {
+        Some(path) => Expression::CString(path.to_string_lossy().into_owned()),
+        None => Expression::CErr(Box::new(Expression::CString(format!(
+            "subprocess.resolve() '{}' not found on PATH",
+            program
+        )))),
+    }))
+}
+
+/// Implementation of subprocess.was_signaled built-in function.
+/// Returns True when the CompletedProcess was terminated by a signal.
+fn subprocess_was_signaled_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.was_signaled() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CompletedProcess { signal, .. } => Ok(ExpressionResult::Value(if signal.is_some() {
+            Expression::CTrue
+        } else {
+            Expression::CFalse
+        })),
+        other => Err(format!(
+            "subprocess.was_signaled() argument must be a CompletedProcess, got {}",
+            variant_name(other)
+        )),
+    }
+}
+
+/// Implementation of subprocess.args built-in function.
+/// Returns the command line that produced a CompletedProcess as a list of
+/// strings, e.g. for logging what was run.
+fn subprocess_args_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.args() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CompletedProcess { args, .. } => Ok(ExpressionResult::Value(Expression::ListValue(
+            args.iter().cloned().map(Expression::CString).collect(),
+        ))),
+        other => Err(format!(
+            "subprocess.args() argument must be a CompletedProcess, got {}",
+            variant_name(other)
+        )),
+    }
+}
+
+/// Shared implementation of subprocess.stdout_lossy and subprocess.stderr_lossy:
+/// whether invalid UTF-8 was replaced with U+FFFD when decoding the given stream
+/// of a CompletedProcess
+fn lossy_flag_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+    builtin: &str,
+    stream: &str,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err(format!("{}() takes exactly 1 argument", builtin));
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CompletedProcess { stdout_lossy, stderr_lossy, .. } => {
+            let lossy = if stream == "stdout" { *stdout_lossy } else { *stderr_lossy };
+            Ok(ExpressionResult::Value(if lossy { Expression::CTrue } else { Expression::CFalse }))
+        }
+        other => Err(format!(
+            "{}() argument must be a CompletedProcess, got {}",
+            builtin,
+            variant_name(other)
+        )),
+    }
+}
+
+/// Implementation of subprocess.stdout_lossy built-in function.
+fn subprocess_stdout_lossy_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    lossy_flag_builtin(args, env, "subprocess.stdout_lossy", "stdout")
+}
+
+/// Implementation of subprocess.stderr_lossy built-in function.
+fn subprocess_stderr_lossy_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    lossy_flag_builtin(args, env, "subprocess.stderr_lossy", "stderr")
+}
+
+/// Parse a process handle argument of a built-in call
+fn parse_handle_argument(value: &Expression, builtin: &str) -> Result<i32, String> {
+    match value {
+        Expression::PopenHandle(handle) => Ok(*handle),
+        _ => Err(format!("{}() first argument must be a process handle", builtin)),
+    }
+}
+
+/// Implementation of subprocess.Popen built-in function.
+/// Spawns the command with stdin piped and returns a handle to the running process.
+/// An optional label names the process in error messages, and a trailing options
+/// map takes the same options as subprocess.run, plus `pipe_stdin`.
+fn subprocess_popen_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    // Validate argument count (1-3 arguments plus an options map expected)
+    if args.is_empty() || args.len() > 4 {
+        return Err("subprocess.Popen() takes 1 to 3 arguments and an optional options map".to_string());
+    }
+
+    let mut evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+
+    // Trailing map: keyword options
+    if evaluated_args.len() > 1 && matches!(evaluated_args.last(), Some(Expression::MapValue(_))) {
+        let keyword_options = evaluated_args.pop().unwrap_or(Expression::CVoid);
+        apply_keyword_options(&keyword_options, "subprocess.Popen", &mut options)?;
+    }
+    if evaluated_args.len() > 3 {
+        return Err("subprocess.Popen() takes 1 to 3 arguments and an optional options map".to_string());
+    }
+
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.Popen", options.splits_command_string())?;
+
+    // Second argument: capture_output (optional, default False)
+    if evaluated_args.len() > 1 {
+        options.capture_output =
+            parse_bool_argument(&evaluated_args[1], "subprocess.Popen", "capture_output")?;
+    }
+
+    // Third argument: label (optional)
+    if evaluated_args.len() > 2 {
+        match &evaluated_args[2] {
+            Expression::CString(label) => options.label = Some(label.clone()),
+            _ => return Err("subprocess.Popen() label argument must be a string".to_string()),
+        }
+    }
+    apply_environment_options(env, &mut options);
+
+    match popen_command(command, options) {
+        Ok(process) => {
+            let handle = env.subprocess.borrow_mut().add_process(process);
+            Ok(ExpressionResult::Value(Expression::PopenHandle(handle)))
+        }
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.finish built-in function.
+/// Closes the process's stdin, waits for it and returns a CompletedProcess.
+/// The handle is released afterwards.
+fn subprocess_finish_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.finish() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.finish")?;
+    let mut process = env
+        .subprocess
+        .borrow_mut()
+        .remove_process(handle)
+        .ok_or_else(|| format!("subprocess.finish() unknown process handle {}", handle))?;
+
+    match process.finish() {
+        Ok(completed_process) => Ok(ExpressionResult::Value(completed_process_to_expression(
+            completed_process,
+        ))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.join_timeout built-in function.
+/// Like subprocess.finish, but waits at most `timeout_ms` milliseconds; a
+/// process still running after that is killed and a timeout CErr is returned.
+/// The handle is released either way.
+fn subprocess_join_timeout_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 2 {
+        return Err("subprocess.join_timeout() takes exactly 2 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.join_timeout")?;
+    let timeout_ms = match &evaluated_args[1] {
+        Expression::CInt(ms) if *ms >= 0 => *ms as u64,
+        _ => {
+            return Err("subprocess.join_timeout() timeout_ms must be a non-negative integer".to_string())
+        }
+    };
+    let mut process = env
+        .subprocess
+        .borrow_mut()
+        .remove_process(handle)
+        .ok_or_else(|| format!("subprocess.join_timeout() unknown process handle {}", handle))?;
+
+    match process.join_timeout(std::time::Duration::from_millis(timeout_ms)) {
+        Ok(completed_process) => Ok(ExpressionResult::Value(completed_process_to_expression(
+            completed_process,
+        ))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.is_alive built-in function.
+/// Reports whether a process spawned by subprocess.Popen is still running,
+/// without waiting for it or releasing its handle.
+fn subprocess_is_alive_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.is_alive() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.is_alive")?;
+    let mut context = env.subprocess.borrow_mut();
+    let process = context
+        .process_mut(handle)
+        .ok_or_else(|| format!("subprocess.is_alive() unknown process handle {}", handle))?;
+    let alive = process
+        .is_alive()
+        .map_err(|e| format!("subprocess.is_alive() {}: {}", process.describe(), e))?;
+    Ok(ExpressionResult::Value(if alive { Expression::CTrue } else { Expression::CFalse }))
+}
+
+/// Implementation of subprocess.stdin_write built-in function.
+/// Writes a string or bytes to the stdin of a process spawned by subprocess.Popen,
+/// optionally closing stdin afterwards.
+fn subprocess_stdin_write_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    // Validate argument count (2-3 arguments expected)
+    if args.len() < 2 || args.len() > 3 {
+        return Err("subprocess.stdin_write() takes 2 to 3 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.stdin_write")?;
+    let data = match &evaluated_args[1] {
+        Expression::CString(data) => data.as_bytes().to_vec(),
+        Expression::CBytes(data) => data.clone(),
+        _ => return Err("subprocess.stdin_write() data argument must be a string or bytes".to_string()),
+    };
+
+    // Third argument: close (optional, default False)
+    let mut close = false;
+    if evaluated_args.len() > 2 {
+        close = parse_bool_argument(&evaluated_args[2], "subprocess.stdin_write", "close")?;
+    }
+
+    let mut context = env.subprocess.borrow_mut();
+    let process = context
+        .process_mut(handle)
+        .ok_or_else(|| format!("subprocess.stdin_write() unknown process handle {}", handle))?;
+
+    match process.write_stdin(&data, close) {
+        Ok(()) => Ok(ExpressionResult::Value(Expression::CVoid)),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Default time subprocess.read_until waits for its sentinel
+const READ_UNTIL_DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+/// Implementation of subprocess.read_until built-in function.
+/// Reads the stdout of a process spawned by subprocess.Popen (with
+/// capture_output) until the sentinel appears, and returns the text read up to
+/// and including it. Gives up with a CErr after `timeout_ms` (default 10s).
+fn subprocess_read_until_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() < 2 || args.len() > 3 {
+        return Err("subprocess.read_until() takes 2 to 3 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.read_until")?;
+    let sentinel = match &evaluated_args[1] {
+        Expression::CString(sentinel) => sentinel.clone(),
+        _ => return Err("subprocess.read_until() sentinel argument must be a string".to_string()),
+    };
+
+    // Third argument: timeout_ms (optional)
+    let mut timeout_ms = READ_UNTIL_DEFAULT_TIMEOUT_MS;
+    if evaluated_args.len() > 2 {
+        timeout_ms = match &evaluated_args[2] {
+            Expression::CInt(ms) if *ms >= 0 => *ms as u64,
+            _ => {
+                return Err("subprocess.read_until() timeout_ms must be a non-negative integer".to_string())
+            }
+        };
+    }
+
+    let mut context = env.subprocess.borrow_mut();
+    let process = context
+        .process_mut(handle)
+        .ok_or_else(|| format!("subprocess.read_until() unknown process handle {}", handle))?;
+
+    match process.read_until(&sentinel, std::time::Duration::from_millis(timeout_ms)) {
+        Ok(text) => Ok(ExpressionResult::Value(Expression::CString(text))),
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.stream_lines built-in function.
+/// Spawns the command and returns a handle for reading its stdout one line at
+/// a time with subprocess.next_line, while the command is still running.
+fn subprocess_stream_lines_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.stream_lines() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let mut options = default_run_options(env);
+    let command =
+        parse_command_argument(&evaluated_args[0], "subprocess.stream_lines", options.splits_command_string())?;
+    apply_environment_options(env, &mut options);
+
+    match stream_lines(command, options) {
+        Ok(stream) => {
+            let handle = env.subprocess.borrow_mut().add_stream(stream);
+            Ok(ExpressionResult::Value(Expression::PopenHandle(handle)))
+        }
+        Err(subprocess_error) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            subprocess_error,
+        ))),
+    }
+}
+
+/// Implementation of subprocess.next_line built-in function.
+/// Returns Just the next stdout line of a stream (without its newline),
+/// or Nothing once the command has closed its output.
+fn subprocess_next_line_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.next_line() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.next_line")?;
+    let mut context = env.subprocess.borrow_mut();
+    let stream = context
+        .stream_mut(handle)
+        .ok_or_else(|| format!("subprocess.next_line() unknown stream handle {}", handle))?;
+
+    match stream.next() {
+        Some(Ok(line)) => Ok(ExpressionResult::Value(Expression::CJust(Box::new(
+            Expression::CString(line),
+        )))),
+        None => Ok(ExpressionResult::Value(Expression::CNothing)),
+        Some(Err(e)) => Ok(ExpressionResult::Value(subprocess_error_to_expression(
+            SubprocessError::OutputCaptureError(format!("{}: {}", stream.describe(), e)),
+        ))),
+    }
+}
+
+/// Implementation of subprocess.close_stream built-in function.
+/// Releases a stream handle, killing the command if it is still running.
+fn subprocess_close_stream_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("subprocess.close_stream() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let handle = parse_handle_argument(&evaluated_args[0], "subprocess.close_stream")?;
+    let stream = env.subprocess.borrow_mut().remove_stream(handle);
+    match stream {
+        Some(_) => Ok(ExpressionResult::Value(Expression::CVoid)),
+        None => Err(format!("subprocess.close_stream() unknown stream handle {}", handle)),
+    }
+}
+
+/// Implementation of subprocess.reap_all built-in function.
+/// Releases every process and stream handle of the environment, killing and
+/// waiting for the processes still running so none is left behind as a
+/// zombie at the end of a script. Returns how many were still running.
+fn subprocess_reap_all_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if !args.is_empty() {
+        return Err("subprocess.reap_all() takes no arguments".to_string());
+    }
+
+    let reaped = env.subprocess.borrow_mut().reap_all();
+    Ok(ExpressionResult::Value(Expression::CInt(
+        i32::try_from(reaped).unwrap_or(i32::MAX),
+    )))
+}
+
+/// Register the io.* built-in functions
+fn register_io_builtins(registry: &mut BuiltinRegistry) {
+    registry.register("io.isatty".to_string(), io_isatty_builtin);
+    registry.register("io.realpath".to_string(), io_realpath_builtin);
+}
+
+/// Implementation of io.isatty built-in function.
+/// Reports whether the interpreter's "stdout" (default) or "stderr" is a terminal.
+fn io_isatty_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    use std::io::IsTerminal;
+
+    if args.len() > 1 {
+        return Err("io.isatty() takes 0 to 1 arguments".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let is_terminal = match evaluated_args.first() {
+        None => std::io::stdout().is_terminal(),
+        Some(Expression::CString(stream)) if stream == "stdout" => std::io::stdout().is_terminal(),
+        Some(Expression::CString(stream)) if stream == "stderr" => std::io::stderr().is_terminal(),
+        Some(_) => return Err("io.isatty() stream must be \"stdout\" or \"stderr\"".to_string()),
+    };
+
+    if is_terminal {
+        Ok(ExpressionResult::Value(Expression::CTrue))
+    } else {
+        Ok(ExpressionResult::Value(Expression::CFalse))
+    }
+}
+
+/// Implementation of io.realpath built-in function.
+/// Returns the absolute path with symlinks resolved, or a CErr if it doesn't exist.
+fn io_realpath_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("io.realpath() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    let path = match &evaluated_args[0] {
+        Expression::CString(path) => path,
+        _ => return Err("io.realpath() path must be a string".to_string()),
+    };
+
+    match std::fs::canonicalize(path) {
+        Ok(real_path) => Ok(ExpressionResult::Value(Expression::CString(
+            real_path.to_string_lossy().into_owned(),
+        ))),
+        Err(e) => Ok(ExpressionResult::Value(Expression::CErr(Box::new(
+            Expression::CString(format!("io.realpath() cannot resolve '{}': {}", path, e)),
+        )))),
+    }
+}
+
+/// Register the os.* built-in functions
+fn register_os_builtins(registry: &mut BuiltinRegistry) {
+    registry.register("os.path.expanduser".to_string(), os_path_expanduser_builtin);
+    registry.register("os.environ".to_string(), os_environ_builtin);
+    registry.register("os.getpid".to_string(), os_getpid_builtin);
+}
+
+/// Register the shutil built-in functions
+fn register_shutil_builtins(registry: &mut BuiltinRegistry) {
+    registry.register("shutil.which_all".to_string(), shutil_which_all_builtin);
+}
+
+/// Implementation of shutil.which_all built-in function.
+/// Returns every executable with the given name on PATH, in search order, so
+/// a program shadowed by an earlier PATH entry can be spotted.
+fn shutil_which_all_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("shutil.which_all() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CString(name) => Ok(ExpressionResult::Value(Expression::ListValue(
+            crate::stdlib::shutil::which_all(name)
+                .into_iter()
+                .map(|path| Expression::CString(path.to_string_lossy().into_owned()))
+                .collect(),
+        ))),
+        _ => Err("shutil.which_all() name must be a string".to_string()),
+    }
+}
+
+/// Implementation of os.path.expanduser built-in function
+fn os_path_expanduser_builtin(
+    args: Vec<Expression>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if args.len() != 1 {
+        return Err("os.path.expanduser() takes exactly 1 argument".to_string());
+    }
+
+    let evaluated_args = match evaluate_arguments(args, env)? {
+        Ok(values) => values,
+        Err(propagated) => return Ok(propagated),
+    };
+
+    match &evaluated_args[0] {
+        Expression::CString(path) => Ok(ExpressionResult::Value(Expression::CString(
+            crate::stdlib::os::expanduser(path),
+        ))),
+        _ => Err("os.path.expanduser() path must be a string".to_string()),
+    }
+}
+
+/// Implementation of os.environ built-in function.
+/// Returns the interpreter's environment variables as a map of strings.
+fn os_environ_builtin(
+    args: Vec<Expression>,
+    _env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if !args.is_empty() {
+        return Err("os.environ() takes no arguments".to_string());
+    }
+
+    // Non-UTF-8 names or values are converted lossily rather than failing the call
+    let entries = std::env::vars_os()
+        .map(|(key, value)| {
+            (
+                Expression::CString(key.to_string_lossy().into_owned()),
+                Expression::CString(value.to_string_lossy().into_owned()),
+            )
+        })
+        .collect();
+
+    Ok(ExpressionResult::Value(Expression::MapValue(entries)))
+}
+
+/// Implementation of os.getpid built-in function.
+/// Returns the process ID of the interpreter itself.
+fn os_getpid_builtin(
+    args: Vec<Expression>,
+    _env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if !args.is_empty() {
+        return Err("os.getpid() takes no arguments".to_string());
+    }
+
+    // PIDs are bounded well below i32::MAX on every supported platform
+    Ok(ExpressionResult::Value(Expression::CInt(std::process::id() as i32)))
+}
+
+/// Monotonic reference point for time.now_ms, fixed on first use
+static TIME_BASELINE: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+/// Register the time.* built-in functions
+fn register_time_builtins(registry: &mut BuiltinRegistry) {
+    registry.register("time.now_ms".to_string(), time_now_ms_builtin);
+}
+
+/// Implementation of time.now_ms built-in function.
+/// Returns monotonic milliseconds elapsed since the interpreter's time baseline.
+fn time_now_ms_builtin(
+    args: Vec<Expression>,
+    _env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    if !args.is_empty() {
+        return Err("time.now_ms() takes no arguments".to_string());
+    }
+
+    let baseline = TIME_BASELINE.get_or_init(std::time::Instant::now);
+    let elapsed_ms = i32::try_from(baseline.elapsed().as_millis()).unwrap_or(i32::MAX);
+    Ok(ExpressionResult::Value(Expression::CInt(elapsed_ms)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::environment::environment::Environment;
+
+    fn create_test_env() -> Environment<Expression> {
+        let mut env = Environment::new();
+        register_builtins(&mut env);
+        env
+    }
+
+    /// Value stored under the string key `name` in the entries of a map
+    pub(crate) fn map_field(entries: &[(Expression, Expression)], name: &str) -> Option<Expression> {
+        entries
+            .iter()
+            .find(|(key, _)| *key == Expression::CString(name.to_string()))
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Runner that records the commands it is given and returns a canned result
+    /// without spawning anything
+    struct MockRunner {
+        calls: std::cell::RefCell<Vec<Vec<String>>>,
+    }
+
+    impl MockRunner {
+        /// Install a new mock as the runner of `env`
+        fn install(env: &Environment<Expression>) -> std::rc::Rc<MockRunner> {
+            let runner = std::rc::Rc::new(MockRunner { calls: Default::default() });
+            env.subprocess.borrow_mut().set_runner(runner.clone());
+            runner
+        }
+    }
+
+    impl crate::stdlib::CommandRunner for MockRunner {
+        fn run(&self, command: Vec<String>, _options: RunOptions) -> Result<CompletedProcess, SubprocessError> {
+            self.calls.borrow_mut().push(command.clone());
+            Ok(CompletedProcess {
+                args: command,
+                returncode: Some(3),
+                stdout: Some("canned\n".to_string()),
+                stderr: Some(String::new()),
+                stdout_bytes: None,
+                stderr_bytes: None,
+                rusage: None,
+                signal: None,
+                stdout_lossy: false,
+                stderr_lossy: false,
+            })
+        }
+    }
+
+    #[test]
+    fn test_builtin_registry_creation() {
+        let registry = get_builtin_registry();
+        assert!(registry.lookup(&"subprocess.run".to_string()).is_some());
+    }
+
+    #[test]
+    fn test_eval_builtin_function_exists() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("echo".to_string()),
+                Expression::CString("test".to_string()),
+            ]),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+        ];
+
+        let result = eval_builtin_function(&"subprocess.run".to_string(), args, &env);
+        assert!(matches!(result, Ok(ExpressionResult::Value(Expression::CompletedProcess { .. }))));
+    }
+
+    #[test]
+    fn test_eval_builtin_function_not_exists() {
+        let env = create_test_env();
+        let args = vec![];
+
+        let result = eval_builtin_function(&"nonexistent.function".to_string(), args, &env);
+        assert_eq!(result, Err(BuiltinError::NotFound("nonexistent.function".to_string())));
+    }
+
+    #[test]
+    fn test_eval_builtin_function_runtime_error() {
+        let env = create_test_env();
+        let result = eval_builtin_function(&"subprocess.run".to_string(), vec![], &env);
+        assert!(matches!(result, Err(BuiltinError::Runtime(ref msg)) if msg.contains("subprocess.run()")));
+    }
+
+    #[test]
+    fn test_subprocess_run_basic_command() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("echo".to_string()),
+                Expression::CString("hello".to_string()),
+            ]),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_ok());
+        
+        match result.unwrap() {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
+                assert_eq!(returncode, 0);
+                assert!(stdout.is_some());
+                assert!(stderr.is_some());
+                assert!(stdout.unwrap().contains("hello"));
+            }
+            _ => panic!("Expected CompletedProcess result"),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_shell_command() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo shell_test".to_string()),
+            Expression::CTrue,  // shell=True
+            Expression::CTrue,  // capture_output=True
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_ok());
+        
+        match result.unwrap() {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
+                assert_eq!(returncode, 0);
+                assert!(stdout.is_some());
+                assert!(stderr.is_some());
+                assert!(stdout.unwrap().contains("shell_test"));
+            }
+            _ => panic!("Expected CompletedProcess result"),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_invalid_arguments() {
+        let env = create_test_env();
+        
+        // Test with no arguments
+        let result = subprocess_run_builtin(vec![], &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+
+        // Test with too many arguments
+        let result = subprocess_run_builtin(vec![
+            Expression::CString("echo".to_string()),
+            Expression::CFalse,
+            Expression::CFalse,
+            Expression::CFalse, // 4th argument - too many
+        ], &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+    }
+
+    #[test]
+    fn test_subprocess_run_invalid_command_type() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CInt(42), // Invalid command type
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("must be a list of strings or a string"));
+    }
+
+    #[test]
+    fn test_subprocess_run_invalid_shell_argument() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo test".to_string()),
+            Expression::CInt(1), // Invalid shell argument type
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("shell argument must be a boolean"));
+    }
+
+    #[test]
+    fn test_subprocess_run_invalid_capture_output_argument() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo test".to_string()),
+            Expression::CFalse,
+            Expression::CString("invalid".to_string()), // Invalid capture_output argument type
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("capture_output argument must be a boolean"));
+    }
+
+    #[test]
+    fn test_subprocess_run_command_not_found() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("nonexistent_command_12345".to_string()),
+            ]),
+            Expression::CFalse, // shell=False
+            Expression::CFalse,  // capture_output=False
+        ];
+
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_ok());
+        
+        // Should return an error wrapped in CErr
+        match result.unwrap() {
+            ExpressionResult::Value(Expression::CErr(error)) => {
+                match *error {
+                    Expression::CString(msg) => {
+                        assert!(msg.contains("Command not found"));
+                        assert!(msg.contains("nonexistent_command_12345"));
+                    }
+                    _ => panic!("Expected string error message"),
+                }
+            }
+            _ => panic!("Expected CErr result for command not found"),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_error_integration_with_rpython_result_system() {
+        let env = create_test_env();
+        
+        // Test InvalidArguments error
+        let result = subprocess_run_builtin(vec![], &env);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+
+        // Test CommandNotFound error converted to CErr
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("nonexistent_cmd_xyz".to_string()),
+            ]),
+        ];
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.is_ok());
+        match result.unwrap() {
+            ExpressionResult::Value(Expression::CErr(error)) => {
+                match *error {
+                    Expression::CString(msg) => {
+                        assert!(msg.starts_with("Command not found:"));
+                        assert!(msg.contains("nonexistent_cmd_xyz"));
+                    }
+                    _ => panic!("Expected string error message"),
+                }
+            }
+            _ => panic!("Expected CErr result"),
+        }
+
+        // Test InvalidArguments error for empty command list
+        let args = vec![
+            Expression::ListValue(vec![]), // Empty command list
+        ];
+        let result = subprocess_run_builtin(args, &env);
+        // This should return an error at the builtin level because empty command list
+        // is caught during argument validation
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("command list cannot be empty"));
+    }
+
+    #[test]
+    fn test_subprocess_ok_true() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![Expression::CString("true".to_string())])];
+
+        let result = subprocess_ok_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CTrue)));
+    }
+
+    #[test]
+    fn test_subprocess_ok_false() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![Expression::CString("false".to_string())])];
+
+        let result = subprocess_ok_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CFalse)));
+    }
+
+    #[test]
+    fn test_subprocess_ok_command_not_found() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("nonexistent_command_12345".to_string()),
+        ])];
+
+        match subprocess_ok_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("Command not found")),
+                _ => panic!("Expected string error message"),
+            },
+            _ => panic!("Expected CErr result for command not found"),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_int_parses_stdout() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("echo".to_string()),
+            Expression::CString("42".to_string()),
+        ])];
+
+        let result = subprocess_run_int_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CInt(42))));
+    }
+
+    #[test]
+    fn test_subprocess_run_int_not_a_number() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("echo".to_string()),
+            Expression::CString("notanumber".to_string()),
+        ])];
+
+        match subprocess_run_int_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("notanumber")),
+                _ => panic!("Expected string error message"),
+            },
+            _ => panic!("Expected CErr result for non-numeric output"),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_bool() {
+        let env = create_test_env();
+        let run_bool = |output: &str| {
+            let args = vec![Expression::ListValue(vec![
+                Expression::CString("echo".to_string()),
+                Expression::CString(output.to_string()),
+            ])];
+            subprocess_run_bool_builtin(args, &env)
+        };
+
+        assert_eq!(run_bool("true"), Ok(ExpressionResult::Value(Expression::CTrue)));
+        assert_eq!(run_bool("0"), Ok(ExpressionResult::Value(Expression::CFalse)));
+        assert_eq!(run_bool("  Yes "), Ok(ExpressionResult::Value(Expression::CTrue)));
+        match run_bool("maybe") {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("'maybe'")),
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result for unrecognized output, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_ints() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("echo '1 2 3'".to_string()), Expression::CTrue];
+        assert_eq!(
+            subprocess_run_ints_builtin(args, &env),
+            Ok(ExpressionResult::Value(Expression::ListValue(vec![
+                Expression::CInt(1),
+                Expression::CInt(2),
+                Expression::CInt(3),
+            ])))
+        );
+
+        let args = vec![Expression::CString("echo '1 x'".to_string()), Expression::CTrue];
+        match subprocess_run_ints_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("'x' at position 1"), "{}", msg),
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result for a non-numeric token, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_io_isatty_returns_boolean() {
+        let env = create_test_env();
+
+        for args in [vec![], vec![Expression::CString("stderr".to_string())]] {
+            match io_isatty_builtin(args, &env) {
+                Ok(ExpressionResult::Value(Expression::CTrue))
+                | Ok(ExpressionResult::Value(Expression::CFalse)) => {}
+                other => panic!("Expected a boolean, got {:?}", other),
+            }
+        }
+
+        let result = io_isatty_builtin(vec![Expression::CString("stdin".to_string())], &env);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_timeout_error_maps_to_cerr() {
+        let error = SubprocessError::TimeoutExpired {
+            command: "sleep".to_string(),
+            seconds: 1.5,
+        };
+        assert_eq!(
+            subprocess_error_to_expression(error),
+            Expression::CErr(Box::new(Expression::CString(
+                "Command 'sleep' timed out after 1.5 seconds".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_map_preserves_order() {
+        let env = create_test_env();
+        let commands = (0..10)
+            .map(|i| {
+                Expression::ListValue(vec![
+                    Expression::CString("echo".to_string()),
+                    Expression::CString(i.to_string()),
+                ])
+            })
+            .collect();
+        let args = vec![Expression::ListValue(commands), Expression::CInt(3)];
+
+        match subprocess_map_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::ListValue(results))) => {
+                assert_eq!(results.len(), 10);
+                for (i, result) in results.into_iter().enumerate() {
+                    match result {
+                        Expression::CompletedProcess { returncode, stdout, .. } => {
+                            assert_eq!(returncode, 0);
+                            assert_eq!(stdout, Some(format!("{}\n", i)));
+                        }
+                        _ => panic!("Expected CompletedProcess result"),
+                    }
+                }
+            }
+            _ => panic!("Expected a list of results"),
+        }
+    }
+
+    #[test]
+    fn test_os_path_expanduser() {
+        let env = create_test_env();
+        let home = std::env::var("HOME").unwrap();
+
+        let result = os_path_expanduser_builtin(vec![Expression::CString("~/foo".to_string())], &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString(format!(
+                "{}/foo",
+                home.trim_end_matches('/')
+            ))))
+        );
+
+        let result = os_path_expanduser_builtin(vec![Expression::CString("/etc/hosts".to_string())], &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString("/etc/hosts".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_run_bytes() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'a\\000b'".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let result = subprocess_run_bytes_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CBytes(vec![b'a', 0, b'b'])))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_run_words() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo '  a  b   c '".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let result = subprocess_run_words_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::ListValue(vec![
+                Expression::CString("a".to_string()),
+                Expression::CString("b".to_string()),
+                Expression::CString("c".to_string()),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_run_firstline() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'v1.2.3\\nother\\n'".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+        let result = subprocess_run_firstline_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CString("v1.2.3".to_string()))));
+
+        let args = vec![Expression::CString("true".to_string())];
+        let result = subprocess_run_firstline_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CString(String::new()))));
+    }
+
+    #[test]
+    fn test_subprocess_run_combined() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo a; echo b >&2; echo c".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+        let result = subprocess_run_combined_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CString("a\nb\nc\n".to_string()))));
+    }
+
+    #[test]
+    fn test_subprocess_popen_finish() {
+        use std::io::Write;
+
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![Expression::CString("cat".to_string())]),
+            Expression::CTrue, // capture_output=True
+        ];
+
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::PopenHandle(handle))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        env.subprocess
+            .borrow_mut()
+            .process_mut(handle)
+            .unwrap()
+            .stdin
+            .as_mut()
+            .unwrap()
+            .write_all(b"from stdin\n")
+            .unwrap();
+
+        let result = subprocess_finish_builtin(vec![Expression::PopenHandle(handle)], &env);
+        match result {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("from stdin\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+
+        // The handle is released once the process is finished
+        let result = subprocess_finish_builtin(vec![Expression::PopenHandle(handle)], &env);
+        assert!(result.unwrap_err().contains("unknown process handle"));
+    }
+
+    #[test]
+    fn test_subprocess_stdin_write() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![Expression::CString("cat".to_string())]),
+            Expression::CTrue, // capture_output=True
+        ];
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        let result = subprocess_stdin_write_builtin(
+            vec![handle.clone(), Expression::CString("hello cat\n".to_string())],
+            &env,
+        );
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CVoid)));
+
+        // Writing with close=True drops stdin, so a further write fails
+        let result = subprocess_stdin_write_builtin(
+            vec![handle.clone(), Expression::CString("bye\n".to_string()), Expression::CTrue],
+            &env,
+        );
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CVoid)));
+        let result = subprocess_stdin_write_builtin(
+            vec![handle.clone(), Expression::CString("too late\n".to_string())],
+            &env,
+        );
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr after stdin was closed, got {:?}", other),
+        }
+
+        match subprocess_finish_builtin(vec![handle], &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some("hello cat\nbye\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_time_now_ms_is_monotonic() {
+        let env = create_test_env();
+
+        let first = match time_now_ms_builtin(vec![], &env) {
+            Ok(ExpressionResult::Value(Expression::CInt(ms))) => ms,
+            other => panic!("Expected CInt, got {:?}", other),
+        };
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = match time_now_ms_builtin(vec![], &env) {
+            Ok(ExpressionResult::Value(Expression::CInt(ms))) => ms,
+            other => panic!("Expected CInt, got {:?}", other),
+        };
+
+        assert!(first >= 0);
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn test_subprocess_run_enforces_environment_allowlist() {
+        let env = create_test_env();
+        register_allowed_command(&env, "echo");
+        env.subprocess.borrow_mut().set_enforce_allowlist(true);
+
+        let allowed = vec![
+            Expression::ListValue(vec![
+                Expression::CString("echo".to_string()),
+                Expression::CString("allowed".to_string()),
+            ]),
+            Expression::CFalse,
+            Expression::CTrue,
+        ];
+        match subprocess_run_builtin(allowed, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("allowed\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+
+        let rejected = vec![Expression::ListValue(vec![
+            Expression::CString("rm".to_string()),
+            Expression::CString("/nonexistent".to_string()),
+        ])];
+        match subprocess_run_builtin(rejected, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => {
+                    assert!(msg.starts_with("Permission denied"));
+                    assert!(msg.contains("rm"));
+                }
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_shell_list_passes_positional_args() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("echo $1".to_string()),
+                Expression::CString("ignored".to_string()),
+                Expression::CString("hello".to_string()),
+            ]),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+        ];
+
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("hello\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_to_tmpfile() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("seq 1 100000".to_string())];
+
+        let path = match subprocess_run_to_tmpfile_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CString(path))) => path,
+            other => panic!("Expected a path, got {:?}", other),
+        };
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(content.lines().count(), 100000);
+        assert_eq!(content.lines().last(), Some("100000"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_with_env() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("FOO".to_string()),
+            Expression::CString("bar".to_string()),
+            Expression::CString("sh -c 'echo $FOO'".to_string()),
+        ];
+
+        match subprocess_run_with_env_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("bar\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+
+        let args = vec![
+            Expression::CString("A=B".to_string()),
+            Expression::CString("bar".to_string()),
+            Expression::CString("true".to_string()),
+        ];
+        assert!(subprocess_run_with_env_builtin(args, &env)
+            .unwrap_err()
+            .contains("invalid variable name"));
+    }
+
+    #[test]
+    fn test_subprocess_run_map() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo out; echo err >&2; exit 2".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+        let entries = match subprocess_run_map_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map result, got {:?}", other),
+        };
+        assert_eq!(map_field(&entries, "returncode"), Some(Expression::CInt(2)));
+        assert_eq!(map_field(&entries, "stdout"), Some(Expression::CString("out\n".to_string())));
+        assert_eq!(map_field(&entries, "stderr"), Some(Expression::CString("err\n".to_string())));
+    }
+
+    #[test]
+    fn test_subprocess_run_kv() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'a=1\\nb=2\\n'".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let result = subprocess_run_kv_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::MapValue(vec![
+                (Expression::CString("a".to_string()), Expression::CString("1".to_string())),
+                (Expression::CString("b".to_string()), Expression::CString("2".to_string())),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_run_kv_strict_rejects_lines_without_equals() {
+        let env = create_test_env();
+        let command = Expression::CString("printf 'a=1\\nnot a pair\\n'".to_string());
+
+        let lenient = subprocess_run_kv_builtin(vec![command.clone(), Expression::CTrue], &env);
+        assert_eq!(
+            lenient,
+            Ok(ExpressionResult::Value(Expression::MapValue(vec![(
+                Expression::CString("a".to_string()),
+                Expression::CString("1".to_string()),
+            )])))
+        );
+
+        let strict = vec![command, Expression::CTrue, Expression::CTrue];
+        match subprocess_run_kv_builtin(strict, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("not a pair")),
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_capture_default_per_mode() {
+        let env = create_test_env();
+        set_default_capture(&env, true, true);
+        set_default_capture(&env, false, false);
+        let run = |args: Vec<Expression>| match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => stdout,
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        };
+
+        let shell = run(vec![Expression::CString("echo shell".to_string()), Expression::CTrue]);
+        assert_eq!(shell, Some("shell\n".to_string()));
+        let direct = run(vec![Expression::CString("echo direct".to_string()), Expression::CFalse]);
+        assert_eq!(direct, None);
+
+        // Passing capture_output still decides
+        let direct = run(vec![Expression::CString("echo direct".to_string()), Expression::CFalse, Expression::CTrue]);
+        assert_eq!(direct, Some("direct\n".to_string()));
+    }
+
+    #[test]
+    fn test_subprocess_run_uses_default_run_options() {
+        let env = create_test_env();
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        set_default_run_options(
+            &env,
+            RunOptions {
+                capture_output: true,
+                cwd: Some(dir.to_string_lossy().into_owned()),
+                ..Default::default()
+            },
+        );
+
+        let pwd = || Expression::ListValue(vec![Expression::CString("pwd".to_string())]);
+        match subprocess_run_builtin(vec![pwd()], &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some(format!("{}\n", dir.display())));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+
+        // Per-call arguments win over the defaults
+        let args = vec![pwd(), Expression::CFalse, Expression::CFalse];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, None);
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_base64() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'a\\000\\377'".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        // b"a\x00\xff" encodes to "YQD/"
+        let result = subprocess_run_base64_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString("YQD/".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_popen_label_in_errors() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![Expression::CString("cat".to_string())]),
+            Expression::CTrue, // capture_output=True
+            Expression::CString("build".to_string()),
+        ];
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        let close = vec![handle.clone(), Expression::CString(String::new()), Expression::CTrue];
+        assert!(subprocess_stdin_write_builtin(close, &env).is_ok());
+
+        let result = subprocess_stdin_write_builtin(
+            vec![handle.clone(), Expression::CString("too late\n".to_string())],
+            &env,
+        );
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("cat [build]")),
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr after stdin was closed, got {:?}", other),
+        }
+
+        let _ = subprocess_finish_builtin(vec![handle], &env);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_rusage() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("x=$(head -c 1000000 /dev/zero | tr '\\0' a); echo ${#x}".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let entries = match subprocess_run_rusage_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        assert_eq!(map_field(&entries, "returncode"), Some(Expression::CInt(0)));
+        assert_eq!(map_field(&entries, "stdout"), Some(Expression::CString("1000000\n".to_string())));
+        match map_field(&entries, "max_rss_kb") {
+            Some(Expression::CInt(max_rss_kb)) => assert!(max_rss_kb > 0),
+            other => panic!("Expected an integer, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_cputime_of_busy_loop() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done; echo $i".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let entries = match subprocess_run_cputime_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        assert_eq!(map_field(&entries, "returncode"), Some(Expression::CInt(0)));
+        assert_eq!(map_field(&entries, "stdout"), Some(Expression::CString("200000\n".to_string())));
+        match map_field(&entries, "cpu_ms") {
+            Some(Expression::CInt(cpu_ms)) => assert!(cpu_ms > 0, "busy loop used no CPU time"),
+            other => panic!("Expected an integer, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_pty_reports_a_terminal() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![Expression::CString("tty".to_string())])];
+
+        match subprocess_run_pty_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert!(stdout.unwrap().starts_with("/dev/"));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_non_string_element_reports_index() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("echo".to_string()),
+            Expression::CString("a".to_string()),
+            Expression::CInt(5),
+        ])];
+
+        let error = subprocess_run_builtin(args, &env).unwrap_err();
+        assert!(error.contains("element 2 is CInt, expected string"), "{}", error);
+    }
+
+    fn run_strict_error(args: Vec<Expression>, env: &Environment<Expression>) -> String {
+        match subprocess_run_strict_builtin(args, env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => msg,
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_strict_success() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("echo".to_string()),
+                Expression::CString("ok".to_string()),
+            ]),
+            Expression::CInt(5000),
+        ];
+
+        match subprocess_run_strict_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("ok\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_strict_timeout() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![
+                Expression::CString("sleep".to_string()),
+                Expression::CString("5".to_string()),
+            ]),
+            Expression::CInt(100),
+        ];
+
+        assert!(run_strict_error(args, &env).contains("timed out"));
+    }
+
+    #[test]
+    fn test_subprocess_run_strict_non_zero_exit() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![Expression::CString("false".to_string())]),
+            Expression::CInt(5000),
+        ];
+
+        let msg = run_strict_error(args, &env);
+        assert!(msg.contains("non-zero exit status 1"), "{}", msg);
+        assert!(!msg.contains("timed out"));
+    }
+
+    #[test]
+    fn test_io_realpath() {
+        let env = create_test_env();
+        let dir = std::env::temp_dir().join(format!("rpython_realpath_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let file = dir.join("file.txt");
+        std::fs::write(&file, "").unwrap();
+
+        // A path with a `..` component resolves to the file itself
+        let indirect = dir.join("sub").join("..").join("file.txt");
+        let result = io_realpath_builtin(
+            vec![Expression::CString(indirect.to_string_lossy().into_owned())],
+            &env,
+        );
+        let expected = std::fs::canonicalize(&file).unwrap();
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString(
+                expected.to_string_lossy().into_owned()
+            )))
+        );
+
+        let missing = dir.join("missing");
+        let result = io_realpath_builtin(
+            vec![Expression::CString(missing.to_string_lossy().into_owned())],
+            &env,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr for a missing path, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_os_environ_contains_path() {
+        let env = create_test_env();
+
+        let entries = match os_environ_builtin(vec![], &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        assert_eq!(map_field(&entries, "PATH"), Some(Expression::CString(std::env::var("PATH").unwrap())));
+
+        assert!(os_environ_builtin(vec![Expression::CInt(1)], &env).is_err());
+    }
+
+    #[test]
+    fn test_subprocess_spawn_detached() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("sleep".to_string()),
+            Expression::CString("2".to_string()),
+        ])];
+
+        let pid = match subprocess_spawn_detached_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CInt(pid))) => pid,
+            other => panic!("Expected a PID, got {:?}", other),
+        };
+        assert!(pid > 0);
+
+        #[cfg(unix)]
+        {
+            use nix::sys::signal::{kill, Signal};
+            kill(nix::unistd::Pid::from_raw(pid), Signal::SIGKILL).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_kill_pid() {
+        let env = create_test_env();
+        let mut child = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        let pid = Expression::CInt(child.id() as i32);
+
+        let result = subprocess_kill_pid_builtin(
+            vec![pid.clone(), Expression::CString("SIGKILL".to_string())],
+            &env,
+        );
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CVoid)));
+        child.wait().unwrap();
+
+        match subprocess_kill_pid_builtin(vec![pid.clone(), Expression::CInt(9)], &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr for a process that no longer exists, got {:?}", other),
+        }
+
+        let bad_signal = vec![pid, Expression::CString("SIGNOPE".to_string())];
+        assert!(subprocess_kill_pid_builtin(bad_signal, &env).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_wait_pid() {
+        let env = create_test_env();
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "exit 7"])
+            .spawn()
+            .unwrap();
+
+        let result = subprocess_wait_pid_builtin(vec![Expression::CInt(child.id() as i32)], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CInt(7))));
+        // Already reaped by wait_pid
+        assert!(child.wait().is_err());
+    }
+
+    #[test]
+    fn test_subprocess_run_json_raw() {
+        let env = create_test_env();
+        let run = |command: &str| {
+            let args = vec![Expression::CString(command.to_string()), Expression::CTrue];
+            match subprocess_run_json_raw_builtin(args, &env) {
+                Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+                other => panic!("Expected a map, got {:?}", other),
+            }
+        };
+
+        let valid = run("echo '{\"n\": 1}'");
+        assert_eq!(
+            map_field(&valid, "json"),
+            Some(Expression::MapValue(vec![(Expression::CString("n".to_string()), Expression::CInt(1))]))
+        );
+        assert_eq!(map_field(&valid, "raw"), Some(Expression::CString("{\"n\": 1}\n".to_string())));
+        assert_eq!(map_field(&valid, "error"), Some(Expression::CVoid));
+        assert_eq!(map_field(&valid, "returncode"), Some(Expression::CInt(0)));
+
+        let partial = run("printf '{\"n\": '");
+        assert_eq!(map_field(&partial, "json"), Some(Expression::CVoid));
+        assert_eq!(map_field(&partial, "raw"), Some(Expression::CString("{\"n\": ".to_string())));
+        assert!(matches!(map_field(&partial, "error"), Some(Expression::CString(_))));
+    }
+
+    #[test]
+    fn test_subprocess_run_jsonl() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString(
+                r#"printf '{"name": "a", "size": 1}\n\n{"name": "b", "tags": [true, null]}\n'"#
+                    .to_string(),
+            ),
+            Expression::CTrue, // shell=True
+        ];
+
+        let result = subprocess_run_jsonl_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::ListValue(vec![
+                Expression::MapValue(vec![
+                    (Expression::CString("name".to_string()), Expression::CString("a".to_string())),
+                    (Expression::CString("size".to_string()), Expression::CInt(1)),
+                ]),
+                Expression::MapValue(vec![
+                    (Expression::CString("name".to_string()), Expression::CString("b".to_string())),
+                    (
+                        Expression::CString("tags".to_string()),
+                        Expression::ListValue(vec![Expression::CTrue, Expression::CVoid]),
+                    ),
+                ]),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_run_jsonl_reports_malformed_line() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString(r#"printf '{"ok": 1}\nnot json\n'"#.to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        match subprocess_run_jsonl_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(msg) => assert!(msg.contains("line 2"), "{}", msg),
+                _ => panic!("Expected string error message"),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_sha256() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'abc'".to_string()),
+            Expression::CTrue, // shell=True
+        ];
+
+        let result = subprocess_run_sha256_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_subprocess_explain_code() {
+        let env = create_test_env();
+        let explain = |code: i32| {
+            match subprocess_explain_code_builtin(vec![Expression::CInt(code)], &env) {
+                Ok(ExpressionResult::Value(Expression::CString(meaning))) => meaning,
+                other => panic!("Expected a string, got {:?}", other),
+            }
+        };
+
+        assert_eq!(explain(0), "success");
+        assert_eq!(explain(1), "general error");
+        assert_eq!(explain(127), "command not found");
+        assert_eq!(explain(130), "terminated by SIGINT");
+        assert_eq!(explain(-9), "killed by SIGKILL");
+    }
+
+    #[test]
+    fn test_subprocess_run_string_without_shell_is_split() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo 'hi there'".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+        ];
+
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("hi there\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+
+        let unclosed = vec![Expression::CString("echo 'hi".to_string())];
+        assert!(subprocess_run_builtin(unclosed, &env).is_err());
+    }
+
+    #[test]
+    fn test_subprocess_stream_lines() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("seq".to_string()),
+            Expression::CString("1".to_string()),
+            Expression::CString("1000000".to_string()),
+        ])];
+        let handle = match subprocess_stream_lines_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a stream handle, got {:?}", other),
+        };
+
+        for expected in ["1", "2", "3"] {
+            let result = subprocess_next_line_builtin(vec![handle.clone()], &env);
+            assert_eq!(
+                result,
+                Ok(ExpressionResult::Value(Expression::CJust(Box::new(Expression::CString(
+                    expected.to_string()
+                )))))
+            );
+        }
+
+        // Closing the stream kills seq long before it would have finished
+        let result = subprocess_close_stream_builtin(vec![handle.clone()], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CVoid)));
+        let result = subprocess_next_line_builtin(vec![handle], &env);
+        assert!(result.unwrap_err().contains("unknown stream handle"));
+    }
+
+    #[test]
+    fn test_subprocess_stream_lines_nothing_at_eof() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("echo done".to_string())];
+        let handle = match subprocess_stream_lines_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a stream handle, got {:?}", other),
+        };
+
+        let result = subprocess_next_line_builtin(vec![handle.clone()], &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CJust(Box::new(Expression::CString(
+                "done".to_string()
+            )))))
+        );
+        let result = subprocess_next_line_builtin(vec![handle], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CNothing)));
+    }
+
+    #[test]
+    fn test_subprocess_equal() {
+        let env = create_test_env();
+        let run_echo = |word: &str| {
+            let args = vec![
+                Expression::ListValue(vec![
+                    Expression::CString("echo".to_string()),
+                    Expression::CString(word.to_string()),
+                ]),
+                Expression::CFalse, // shell=False
+                Expression::CTrue,  // capture_output=True
+            ];
+            match subprocess_run_builtin(args, &env) {
+                Ok(ExpressionResult::Value(completed @ Expression::CompletedProcess { .. })) => completed,
+                other => panic!("Expected CompletedProcess result, got {:?}", other),
+            }
+        };
+
+        let result = subprocess_equal_builtin(vec![run_echo("same"), run_echo("same")], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CTrue)));
+
+        let result = subprocess_equal_builtin(vec![run_echo("one"), run_echo("other")], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CFalse)));
+
+        // Different commands with the same outcome are equal
+        let printf = match subprocess_run_builtin(
+            vec![Expression::CString("printf 'same\\n'".to_string()), Expression::CFalse, Expression::CTrue],
+            &env,
+        ) {
+            Ok(ExpressionResult::Value(completed)) => completed,
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        };
+        let result = subprocess_equal_builtin(vec![run_echo("same"), printf], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CTrue)));
+
+        let result = subprocess_equal_builtin(vec![run_echo("one"), Expression::CInt(0)], &env);
+        assert!(result.unwrap_err().contains("second argument must be a CompletedProcess"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_diff() {
+        let env = create_test_env();
+        let diff = |a: &str, b: &str| {
+            let args = vec![Expression::CString(a.to_string()), Expression::CString(b.to_string())];
+            match subprocess_diff_builtin(args, &env) {
+                Ok(ExpressionResult::Value(Expression::CString(diff))) => diff,
+                other => panic!("Expected a diff, got {:?}", other),
+            }
+        };
+
+        let changed = diff("printf 'a\\nb\\n'", "printf 'a\\nc\\n'");
+        assert!(changed.contains("\n-b\n"), "{}", changed);
+        assert!(changed.contains("\n+c\n"), "{}", changed);
+        assert!(changed.contains(" a\n"), "{}", changed);
+
+        assert_eq!(diff("echo same", "printf 'same\\n'"), "");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_run_path_prepend() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("r-python-path-prepend-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("mytool");
+        std::fs::write(&tool, "#!/bin/sh\necho fake mytool\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let env = create_test_env();
+        let dir_name = dir.to_string_lossy().to_string();
+        let options = || {
+            Expression::MapValue(vec![(
+                Expression::CString("path_prepend".to_string()),
+                Expression::CString(dir_name.clone()),
+            )])
+        };
+
+        let args = vec![
+            Expression::CString("mytool".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+            options(),
+        ];
+        let result = subprocess_run_builtin(args, &env);
+
+        // The rest of PATH is kept after the prepended directory
+        let args = vec![
+            Expression::CString("printenv PATH".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+            options(),
+        ];
+        let path_result = subprocess_run_builtin(args, &env);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match result {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("fake mytool\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+        match path_result {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                let expected = format!("{}:{}\n", dir_name, std::env::var("PATH").unwrap());
+                assert_eq!(stdout, Some(expected));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_rejects_unknown_options() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("true".to_string()),
+            Expression::MapValue(vec![(
+                Expression::CString("no_such_option".to_string()),
+                Expression::CTrue,
+            )]),
+        ];
+        let result = subprocess_run_builtin(args, &env);
+        assert!(result.unwrap_err().contains("unexpected option 'no_such_option'"));
+    }
+
+    #[test]
+    fn test_subprocess_run_outlen() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("head -c 1000 /dev/zero".to_string())];
+        let result = subprocess_run_outlen_builtin(args, &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CInt(1000))));
+    }
+
+    #[test]
+    fn test_subprocess_run_throughput() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("head -c 1000000 /dev/zero".to_string())];
+
+        let entries = match subprocess_run_throughput_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        assert_eq!(entries[0], (Expression::CString("bytes".to_string()), Expression::CInt(1000000)));
+        match &entries[1] {
+            (key, Expression::CInt(elapsed_ms)) if *key == Expression::CString("elapsed_ms".to_string()) => {
+                assert!(*elapsed_ms >= 0)
+            }
+            other => panic!("Expected elapsed_ms, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_stderr_file() {
+        let path = std::env::temp_dir().join(format!("r-python-err-{}.log", std::process::id()));
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo out; echo err >&2".to_string()),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+            Expression::MapValue(vec![(
+                Expression::CString("stderr_file".to_string()),
+                Expression::CString(path.to_string_lossy().to_string()),
+            )]),
+        ];
+        let result = subprocess_run_builtin(args, &env);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CompletedProcess {
+                args: vec!["sh".to_string(), "-c".to_string(), "echo out; echo err >&2".to_string()],
+                returncode: 0,
+                stdout: Some("out\n".to_string()),
+                stderr: None,
+                signal: None,
+                stdout_lossy: false,
+                stderr_lossy: false,
+            }))
+        );
+        assert_eq!(log, "err\n");
+    }
+
+    #[test]
+    fn test_subprocess_args_of_completed_process() {
+        let env = create_test_env();
+        let command = ["echo", "hello world", "--flag"];
+        let args = vec![Expression::ListValue(
+            command.iter().map(|arg| Expression::CString(arg.to_string())).collect(),
+        )];
+        let completed = match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(completed @ Expression::CompletedProcess { .. })) => completed,
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        };
+
+        let result = subprocess_args_builtin(vec![completed], &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::ListValue(
+                command.iter().map(|arg| Expression::CString(arg.to_string())).collect()
+            )))
+        );
+        assert!(subprocess_args_builtin(vec![Expression::CInt(1)], &env)
+            .unwrap_err()
+            .contains("must be a CompletedProcess"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_lossy_flags() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'ok\\377'; printf 'fine' >&2".to_string()),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+        ];
+        let completed = match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(completed @ Expression::CompletedProcess { .. })) => completed,
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        };
+
+        assert_eq!(
+            subprocess_stdout_lossy_builtin(vec![completed.clone()], &env),
+            Ok(ExpressionResult::Value(Expression::CTrue))
+        );
+        assert_eq!(
+            subprocess_stderr_lossy_builtin(vec![completed], &env),
+            Ok(ExpressionResult::Value(Expression::CFalse))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_was_signaled() {
+        let env = create_test_env();
+        let run_shell = |script: &str| {
+            let args = vec![Expression::CString(script.to_string()), Expression::CTrue];
+            match subprocess_run_builtin(args, &env) {
+                Ok(ExpressionResult::Value(completed @ Expression::CompletedProcess { .. })) => completed,
+                other => panic!("Expected CompletedProcess result, got {:?}", other),
+            }
+        };
+
+        let killed = run_shell("kill -KILL $$");
+        assert!(matches!(killed, Expression::CompletedProcess { signal: Some(9), .. }));
+        let result = subprocess_was_signaled_builtin(vec![killed], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CTrue)));
+
+        let failed = run_shell("exit 9");
+        let result = subprocess_was_signaled_builtin(vec![failed], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CFalse)));
+    }
+
+    #[test]
+    fn test_subprocess_run_csv() {
+        let env = create_test_env();
+        let row = |fields: &[&str]| {
+            Expression::ListValue(fields.iter().map(|field| Expression::CString(field.to_string())).collect())
+        };
+
+        let args = vec![Expression::CString("printf 'a,b\\n1,2\\n'".to_string())];
+        let result = subprocess_run_csv_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::ListValue(vec![row(&["a", "b"]), row(&["1", "2"])])))
+        );
+
+        // Quoted fields may contain the delimiter
+        let args = vec![
+            Expression::CString("printf 'x;\"y;z\"\\n'".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CString(";".to_string()),
+        ];
+        let result = subprocess_run_csv_builtin(args, &env);
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::ListValue(vec![row(&["x", "y;z"])])))
+        );
+    }
+
+    static STUB_CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    /// Stand-in for subprocess.run that records the call and then runs the real
+    /// built-in, so other tests running concurrently are unaffected
+    fn recording_run_stub(
+        args: Vec<Expression>,
+        env: &Environment<Expression>,
+    ) -> Result<ExpressionResult, String> {
+        STUB_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        subprocess_run_builtin(args, env)
+    }
+
+    #[test]
+    fn test_reregister_replaces_builtin() {
+        let env = create_test_env();
+        let name = "subprocess.run".to_string();
+        let original = reregister(&name, recording_run_stub).unwrap();
+
+        let before = STUB_CALLS.load(std::sync::atomic::Ordering::SeqCst);
+        let result = eval_builtin_function(&name, vec![Expression::CString("true".to_string())], &env);
+        let after = STUB_CALLS.load(std::sync::atomic::Ordering::SeqCst);
+        reregister(&name, original);
+
+        assert!(matches!(result, Ok(ExpressionResult::Value(Expression::CompletedProcess { .. }))));
+        assert!(after > before, "the stub was not called");
+    }
+
+    #[test]
+    fn test_subprocess_run_check_reports_stderr() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("echo 'no such table' >&2; exit 1".to_string()),
+            Expression::CTrue,  // shell=True
+            Expression::CFalse, // capture_output=False
+            Expression::MapValue(vec![(Expression::CString("check".to_string()), Expression::CTrue)]),
+        ];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => match *error {
+                Expression::CString(message) => {
+                    assert!(message.contains("returned non-zero exit status 1"), "got: {}", message);
+                    assert!(message.contains("no such table"), "got: {}", message);
+                }
+                other => panic!("Expected an error message, got {:?}", other),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_template() {
+        let path = std::env::temp_dir().join(format!("r-python-template-{}.txt", std::process::id()));
+        std::fs::write(&path, "plain line\nneedle; $(rm -rf x) 'quoted' *\nother line\n").unwrap();
+
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("grep -F {} {}".to_string()),
+            Expression::ListValue(vec![
+                Expression::CString("needle; $(rm -rf x) 'quoted' *".to_string()),
+                Expression::CString(path.to_string_lossy().to_string()),
+            ]),
+        ];
+        let result = subprocess_run_template_builtin(args, &env);
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("needle; $(rm -rf x) 'quoted' *\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_template_passes_values_as_single_arguments() {
         let env = create_test_env();
+        let runner = MockRunner::install(&env);
+        set_default_run_options(&env, RunOptions { shell: true, split_string: false, ..RunOptions::default() });
+
         let args = vec![
-            Expression::CString("echo test".to_string()),
-            Expression::CFalse,
-            Expression::CString("invalid".to_string()), // Invalid capture_output argument type
+            Expression::CString("grep {} file".to_string()),
+            Expression::ListValue(vec![Expression::CString("a b; rm -rf /".to_string())]),
         ];
+        assert!(matches!(
+            subprocess_run_template_builtin(args, &env),
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { .. }))
+        ));
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![vec!["grep".to_string(), "a b; rm -rf /".to_string(), "file".to_string()]]
+        );
+        assert!(!env.subprocess.borrow().last_options().unwrap().shell);
+    }
 
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("capture_output argument must be a boolean"));
+    #[test]
+    fn test_os_getpid() {
+        let env = create_test_env();
+        let first = os_getpid_builtin(vec![], &env);
+        let second = os_getpid_builtin(vec![], &env);
+
+        match first {
+            Ok(ExpressionResult::Value(Expression::CInt(pid))) => assert!(pid > 0),
+            ref other => panic!("Expected CInt result, got {:?}", other),
+        }
+        assert_eq!(first, second);
     }
 
     #[test]
-    fn test_subprocess_run_command_not_found() {
+    #[cfg(unix)]
+    fn test_subprocess_run_env_keep() {
+        assert!(std::env::var_os("HOME").is_some(), "the test needs HOME set in the parent");
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("env".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CTrue,  // capture_output=True
+            Expression::MapValue(vec![(
+                Expression::CString("env_keep".to_string()),
+                Expression::ListValue(vec![Expression::CString("PATH".to_string())]),
+            )]),
+        ];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout: Some(stdout), .. })) => {
+                assert!(stdout.contains("PATH="));
+                assert!(!stdout.lines().any(|line| line.starts_with("HOME=")));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_expect_code() {
         let env = create_test_env();
+        let expect = |command: &str, code: i32| {
+            subprocess_expect_code_builtin(
+                vec![Expression::CString(command.to_string()), Expression::CInt(code)],
+                &env,
+            )
+        };
+
+        assert_eq!(expect("false", 1), Ok(ExpressionResult::Value(Expression::CTrue)));
+        assert_eq!(expect("true", 1), Ok(ExpressionResult::Value(Expression::CFalse)));
+        match expect("nonexistent_command_12345", 1) {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr for a missing command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_pipeline() {
+        let env = create_test_env();
+        let args = vec![Expression::ListValue(vec![
+            Expression::CString("echo hello pipeline".to_string()),
+            Expression::CString("tr a-z A-Z".to_string()),
+        ])];
+        match subprocess_pipeline_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("HELLO PIPELINE\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+
         let args = vec![
             Expression::ListValue(vec![
-                Expression::CString("nonexistent_command_12345".to_string()),
+                Expression::CString("echo start".to_string()),
+                Expression::CString("sleep 10".to_string()),
             ]),
+            Expression::CInt(200),
+        ];
+        match subprocess_pipeline_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => {
+                assert!(matches!(*error, Expression::CString(ref message) if message.contains("timed out")));
+            }
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_read_until() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::ListValue(vec![Expression::CString("sh".to_string())]),
+            Expression::CTrue, // capture_output=True
+        ];
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        let script = "echo working; printf '$ '\n";
+        subprocess_stdin_write_builtin(vec![handle.clone(), Expression::CString(script.to_string())], &env)
+            .unwrap();
+        let result = subprocess_read_until_builtin(
+            vec![handle.clone(), Expression::CString("$ ".to_string())],
+            &env,
+        );
+        assert_eq!(
+            result,
+            Ok(ExpressionResult::Value(Expression::CString("working\n$ ".to_string())))
+        );
+
+        let result = subprocess_read_until_builtin(
+            vec![handle.clone(), Expression::CString("never".to_string()), Expression::CInt(50)],
+            &env,
+        );
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr after the timeout, got {:?}", other),
+        }
+
+        subprocess_finish_builtin(vec![handle], &env).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_reap_all() {
+        let env = create_test_env();
+        let popen = |command: &str| {
+            let args = vec![Expression::CString(command.to_string())];
+            match subprocess_popen_builtin(args, &env) {
+                Ok(ExpressionResult::Value(Expression::PopenHandle(handle))) => handle,
+                other => panic!("Expected a process handle, got {:?}", other),
+            }
+        };
+        let running: Vec<i32> = (0..3).map(|_| popen("sleep 30")).collect();
+        let exited = popen("true");
+        let pids: Vec<u32> = running
+            .iter()
+            .map(|&handle| env.subprocess.borrow_mut().process_mut(handle).unwrap().child.id())
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        let result = subprocess_reap_all_builtin(vec![], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CInt(3))));
+
+        // Every handle is released and every process is gone, not a zombie
+        for handle in running.iter().chain([&exited]) {
+            assert!(env.subprocess.borrow_mut().process_mut(*handle).is_none());
+        }
+        for pid in pids {
+            let pid = nix::unistd::Pid::from_raw(pid as i32);
+            assert_eq!(nix::sys::signal::kill(pid, None), Err(nix::errno::Errno::ESRCH));
+        }
+        assert_eq!(subprocess_reap_all_builtin(vec![], &env), Ok(ExpressionResult::Value(Expression::CInt(0))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_is_alive() {
+        let env = create_test_env();
+        let handle = match subprocess_popen_builtin(vec![Expression::CString("sleep 1".to_string())], &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+        let is_alive = || subprocess_is_alive_builtin(vec![handle.clone()], &env);
+
+        assert_eq!(is_alive(), Ok(ExpressionResult::Value(Expression::CTrue)));
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        assert_eq!(is_alive(), Ok(ExpressionResult::Value(Expression::CFalse)));
+        assert_eq!(is_alive(), Ok(ExpressionResult::Value(Expression::CFalse)));
+
+        // The exit status is still there for finish()
+        assert!(matches!(
+            subprocess_finish_builtin(vec![handle.clone()], &env),
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_subprocess_join_timeout() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("sleep 5".to_string())];
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        let start = std::time::Instant::now();
+        let result = subprocess_join_timeout_builtin(vec![handle.clone(), Expression::CInt(200)], &env);
+        assert!(start.elapsed() < std::time::Duration::from_secs(4));
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => {
+                assert!(matches!(*error, Expression::CString(ref message) if message.contains("timed out")));
+            }
+            other => panic!("Expected a timeout CErr, got {:?}", other),
+        }
+
+        // The killed process's handle is released
+        let result = subprocess_join_timeout_builtin(vec![handle, Expression::CInt(200)], &env);
+        assert!(result.unwrap_err().contains("unknown process handle"));
+    }
+
+    #[test]
+    fn test_subprocess_run_bytes_input() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("cat".to_string()),
             Expression::CFalse, // shell=False
-            Expression::CFalse,  // capture_output=False
+            Expression::CTrue,  // capture_output=True
+            Expression::MapValue(vec![(
+                Expression::CString("input".to_string()),
+                Expression::CBytes(vec![b'a', 0, b'b', b'\n']),
+            )]),
         ];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("a\0b\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
 
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_ok());
-        
-        // Should return an error wrapped in CErr
-        match result.unwrap() {
-            ExpressionResult::Value(Expression::CErr(error)) => {
-                match *error {
-                    Expression::CString(msg) => {
-                        assert!(msg.contains("Command not found"));
-                        assert!(msg.contains("nonexistent_command_12345"));
-                    }
-                    _ => panic!("Expected string error message"),
-                }
+    #[test]
+    fn test_subprocess_last_options() {
+        let env = create_test_env();
+        let result = subprocess_last_options_builtin(vec![], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::CVoid)));
+
+        let args = vec![
+            Expression::CString("true".to_string()),
+            Expression::CFalse, // shell=False
+            Expression::CFalse, // capture_output=False
+        ];
+        assert!(subprocess_run_builtin(args, &env).is_ok());
+
+        let entries = match subprocess_last_options_builtin(vec![], &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => entries,
+            other => panic!("Expected a map of options, got {:?}", other),
+        };
+        // Without capture_output the streams are inherited, hence stdout is None
+        assert_eq!(map_field(&entries, "capture_output"), Some(Expression::CFalse));
+        assert_eq!(map_field(&entries, "stdout"), Some(Expression::CString("inherit".to_string())));
+        assert_eq!(map_field(&entries, "stderr"), Some(Expression::CString("inherit".to_string())));
+        assert_eq!(map_field(&entries, "timeout_ms"), Some(Expression::CVoid));
+
+        // Helpers that capture for themselves report it too
+        let args = vec![Expression::CString("echo hi".to_string())];
+        assert!(subprocess_run_firstline_builtin(args, &env).is_ok());
+        match subprocess_last_options_builtin(vec![], &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => {
+                assert!(entries.contains(&(
+                    Expression::CString("stdout".to_string()),
+                    Expression::CString("pipe".to_string())
+                )));
             }
-            _ => panic!("Expected CErr result for command not found"),
+            other => panic!("Expected a map of options, got {:?}", other),
         }
     }
 
     #[test]
-    fn test_subprocess_error_integration_with_rpython_result_system() {
+    fn test_subprocess_run_split_string() {
         let env = create_test_env();
-        
-        // Test InvalidArguments error
-        let result = subprocess_run_builtin(vec![], &env);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("takes 1 to 3 arguments"));
+        let run = |split_string: Expression| {
+            let args = vec![
+                Expression::CString("echo split me".to_string()),
+                Expression::CFalse, // shell=False
+                Expression::CTrue,  // capture_output=True
+                Expression::MapValue(vec![(Expression::CString("split_string".to_string()), split_string)]),
+            ];
+            subprocess_run_builtin(args, &env)
+        };
 
-        // Test CommandNotFound error converted to CErr
+        // Split with shlex by default
+        match run(Expression::CTrue) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("split me\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+
+        // Literal: the whole string names the executable, which doesn't exist
+        match run(Expression::CFalse) {
+            Ok(ExpressionResult::Value(Expression::CErr(error))) => {
+                assert!(matches!(*error, Expression::CString(ref message) if message.contains("echo split me")));
+            }
+            other => panic!("Expected CErr for a missing program, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subprocess_run_cpu_limit() {
+        let env = create_test_env();
         let args = vec![
-            Expression::ListValue(vec![
-                Expression::CString("nonexistent_cmd_xyz".to_string()),
-            ]),
+            Expression::CString("while :; do :; done".to_string()),
+            Expression::CTrue,  // shell=True
+            Expression::CFalse, // capture_output=False
+            Expression::MapValue(vec![(Expression::CString("cpu_limit".to_string()), Expression::CInt(1))]),
         ];
-        let result = subprocess_run_builtin(args, &env);
-        assert!(result.is_ok());
-        match result.unwrap() {
-            ExpressionResult::Value(Expression::CErr(error)) => {
-                match *error {
-                    Expression::CString(msg) => {
-                        assert!(msg.starts_with("Command not found:"));
-                        assert!(msg.contains("nonexistent_cmd_xyz"));
-                    }
-                    _ => panic!("Expected string error message"),
-                }
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { signal, .. })) => {
+                assert_eq!(signal, Some(nix::libc::SIGXCPU));
             }
-            _ => panic!("Expected CErr result"),
+            other => panic!("Expected CompletedProcess, got {:?}", other),
         }
 
-        // Test InvalidArguments error for empty command list
         let args = vec![
-            Expression::ListValue(vec![]), // Empty command list
+            Expression::CString("true".to_string()),
+            Expression::MapValue(vec![(Expression::CString("cpu_limit".to_string()), Expression::CInt(0))]),
         ];
-        let result = subprocess_run_builtin(args, &env);
-        // This should return an error at the builtin level because empty command list
-        // is caught during argument validation
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("command list cannot be empty"));
+        assert!(subprocess_run_builtin(args, &env).unwrap_err().contains("cpu_limit"));
+    }
+
+    #[test]
+    fn test_subprocess_popen_pipe_stdin_option() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("true".to_string()),
+            Expression::CTrue, // capture_output=True
+            Expression::MapValue(vec![(Expression::CString("pipe_stdin".to_string()), Expression::CFalse)]),
+        ];
+        let handle = match subprocess_popen_builtin(args, &env) {
+            Ok(ExpressionResult::Value(handle @ Expression::PopenHandle(_))) => handle,
+            other => panic!("Expected a process handle, got {:?}", other),
+        };
+
+        let result = subprocess_stdin_write_builtin(vec![handle.clone(), Expression::CString("x".to_string())], &env);
+        match result {
+            Ok(ExpressionResult::Value(Expression::CErr(_))) => {}
+            other => panic!("Expected CErr writing to an unpiped stdin, got {:?}", other),
+        }
+        assert!(matches!(
+            subprocess_finish_builtin(vec![handle], &env),
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_subprocess_run_progress() {
+        let env = create_test_env();
+        let args = vec![Expression::CString("seq 1 50".to_string())];
+        match subprocess_run_progress_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => {
+                assert!(entries.contains(&(Expression::CString("lines".to_string()), Expression::CInt(50))));
+                assert!(entries.contains(&(Expression::CString("returncode".to_string()), Expression::CInt(0))));
+            }
+            other => panic!("Expected a map result, got {:?}", other),
+        }
+
+        // The last line counts even without a trailing newline
+        let args = vec![Expression::CString("printf 'a\\nb'".to_string()), Expression::CTrue];
+        match subprocess_run_progress_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => {
+                assert!(entries.contains(&(Expression::CString("lines".to_string()), Expression::CInt(2))));
+            }
+            other => panic!("Expected a map result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shutil_which_all() {
+        let env = create_test_env();
+        match shutil_which_all_builtin(vec![Expression::CString("sh".to_string())], &env) {
+            Ok(ExpressionResult::Value(Expression::ListValue(paths))) => {
+                assert!(!paths.is_empty());
+                assert!(paths
+                    .iter()
+                    .all(|path| matches!(path, Expression::CString(path) if path.ends_with("/sh"))));
+            }
+            other => panic!("Expected a list of paths, got {:?}", other),
+        }
+
+        let result = shutil_which_all_builtin(vec![Expression::CString("no_such_tool_xyz".to_string())], &env);
+        assert_eq!(result, Ok(ExpressionResult::Value(Expression::ListValue(vec![]))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_strip_ansi_option() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf '\\033[31mred\\033[0m'".to_string()),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+            Expression::MapValue(vec![(Expression::CString("strip_ansi".to_string()), Expression::CTrue)]),
+        ];
+
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some("red".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_uid_option() {
+        let env = create_test_env();
+        let run = |uid: Expression| {
+            let args = vec![
+                Expression::CString("id -u".to_string()),
+                Expression::CFalse, // shell=False
+                Expression::CTrue,  // capture_output=True
+                Expression::MapValue(vec![(Expression::CString("uid".to_string()), uid)]),
+            ];
+            subprocess_run_builtin(args, &env)
+        };
+
+        let own_uid = nix::unistd::geteuid().as_raw();
+        match run(Expression::CInt(own_uid as i32)) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some(format!("{}\n", own_uid)));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+        assert!(run(Expression::CInt(-1)).unwrap_err().contains("non-negative"));
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    #[test]
+    fn test_subprocess_run_line_prefix_option() {
+        let env = create_test_env();
+        let args = vec![
+            Expression::CString("printf 'x\\ny\\n'".to_string()),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+            Expression::MapValue(vec![(
+                Expression::CString("line_prefix".to_string()),
+                Expression::CString("[build] ".to_string()),
+            )]),
+        ];
+
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some("[build] x\n[build] y\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_stdin_option() {
+        let env = create_test_env();
+        let run = |target: &str| {
+            let args = vec![
+                Expression::CString("cat".to_string()),
+                Expression::CFalse, // shell=False
+                Expression::CTrue,  // capture_output=True
+                Expression::MapValue(vec![(
+                    Expression::CString("stdin".to_string()),
+                    Expression::CString(target.to_string()),
+                )]),
+            ];
+            subprocess_run_builtin(args, &env)
+        };
+
+        match run("null") {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some(String::new()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+        assert!(run("keyboard").unwrap_err().contains("stdin option"));
+    }
+
+    #[test]
+    fn test_subprocess_run_uses_environment_runner() {
+        let env = create_test_env();
+        let runner = MockRunner::install(&env);
+
+        // The program does not exist, so only the mock can produce a result
+        let args = vec![Expression::CString("r-python-no-such-program --flag".to_string())];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. })) => {
+                assert_eq!(returncode, 3);
+                assert_eq!(stdout, Some("canned\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![vec!["r-python-no-such-program".to_string(), "--flag".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_output_counting_builtins_use_environment_runner() {
+        let env = create_test_env();
+        let runner = MockRunner::install(&env);
+        let command = || vec![Expression::CString("r-python-no-such-program".to_string())];
+
+        // "canned\n" is 7 bytes
+        assert_eq!(
+            subprocess_run_outlen_builtin(command(), &env),
+            Ok(ExpressionResult::Value(Expression::CInt(7)))
+        );
+        match subprocess_run_throughput_builtin(command(), &env) {
+            Ok(ExpressionResult::Value(Expression::MapValue(entries))) => {
+                assert!(entries.contains(&(Expression::CString("bytes".to_string()), Expression::CInt(7))));
+            }
+            other => panic!("Expected a map result, got {:?}", other),
+        }
+        assert_eq!(runner.calls.borrow().len(), 2);
+
+        // Counting streams the output of a direct command, so the shell is refused
+        set_default_run_options(&env, RunOptions { shell: true, ..RunOptions::default() });
+        assert!(subprocess_run_outlen_builtin(command(), &env).unwrap_err().contains("shell"));
+        assert!(subprocess_run_throughput_builtin(command(), &env).unwrap_err().contains("shell"));
+        assert_eq!(runner.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_subprocess_resolve() {
+        let env = create_test_env();
+
+        match subprocess_resolve_builtin(vec![Expression::CString("sh -c true".to_string())], &env) {
+            Ok(ExpressionResult::Value(Expression::CString(path))) => {
+                let path = std::path::Path::new(&path);
+                assert!(path.is_absolute());
+                assert!(path.ends_with("sh"));
+            }
+            other => panic!("Expected CString, got {:?}", other),
+        }
+
+        let missing = vec![Expression::ListValue(vec![Expression::CString("no_such_tool_xyz".to_string())])];
+        assert!(matches!(
+            subprocess_resolve_builtin(missing, &env),
+            Ok(ExpressionResult::Value(Expression::CErr(_)))
+        ));
+    }
+
+    #[test]
+    fn test_success_codes_accept_grep_no_match() {
+        let env = create_test_env();
+        let grep = || Expression::CString("grep -q needle /dev/null".to_string());
+        let success_codes = |codes: Vec<i32>| {
+            Expression::MapValue(vec![
+                (Expression::CString("check".to_string()), Expression::CTrue),
+                (
+                    Expression::CString("success_codes".to_string()),
+                    Expression::ListValue(codes.into_iter().map(Expression::CInt).collect()),
+                ),
+            ])
+        };
+
+        // grep exits with 1 when nothing matched
+        match subprocess_run_builtin(vec![grep(), success_codes(vec![0, 1])], &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { returncode, .. })) => {
+                assert_eq!(returncode, 1)
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+        assert!(matches!(
+            subprocess_run_builtin(vec![grep(), success_codes(vec![0])], &env),
+            Ok(ExpressionResult::Value(Expression::CErr(_)))
+        ));
+
+        assert!(matches!(
+            subprocess_ok_builtin(vec![grep()], &env),
+            Ok(ExpressionResult::Value(Expression::CFalse))
+        ));
+        set_default_run_options(&env, RunOptions { success_codes: vec![0, 1], ..RunOptions::default() });
+        assert!(matches!(
+            subprocess_ok_builtin(vec![grep()], &env),
+            Ok(ExpressionResult::Value(Expression::CTrue))
+        ));
+        assert!(matches!(
+            subprocess_run_result_builtin(vec![grep()], &env),
+            Ok(ExpressionResult::Value(Expression::COk(_)))
+        ));
+    }
+
+    #[test]
+    fn test_subprocess_pkill_without_matches() {
+        let env = create_test_env();
+        let name = Expression::CString(format!("rpy-none-{}", std::process::id()));
+        assert!(matches!(
+            subprocess_pkill_builtin(vec![name], &env),
+            Ok(ExpressionResult::Value(Expression::CInt(0)))
+        ));
+        assert!(subprocess_pkill_builtin(vec![Expression::CInt(1)], &env).is_err());
+    }
+
+    #[test]
+    fn test_output_sink_receives_captured_lines() {
+        use std::sync::Mutex;
+
+        static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        fn collect(line: &str) {
+            LINES.lock().unwrap().push(line.to_string());
+        }
+
+        let env = create_test_env();
+        set_output_sink(&env, collect);
+        let args = vec![
+            Expression::CString("printf 'a\\nb\\n'".to_string()),
+            Expression::CTrue, // shell=True
+            Expression::CTrue, // capture_output=True
+        ];
+        match subprocess_run_builtin(args, &env) {
+            Ok(ExpressionResult::Value(Expression::CompletedProcess { stdout, .. })) => {
+                assert_eq!(stdout, Some("a\nb\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess, got {:?}", other),
+        }
+        assert_eq!(*LINES.lock().unwrap(), vec!["a".to_string(), "b".to_string()]);
+    }
+}
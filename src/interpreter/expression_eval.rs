@@ -1,3 +1,4 @@
+use super::builtins::BuiltinError;
 use super::statement_execute::Computation;
 use crate::environment::environment::Environment;
 use crate::ir::ast::{Expression, Name};
@@ -33,7 +34,9 @@ pub fn eval(exp: Expression, env: &Environment<Expression>) -> Result<Expression
         Expression::IsNothing(e) => eval_isnothing_expression(*e, env),
         Expression::FuncCall(name, args) => eval_function_call(name, args, env),
         Expression::ListValue(values) => eval_list_value(values, env),
+        Expression::MapValue(entries) => eval_map_value(entries, env),
         Expression::CompletedProcess { .. } => Ok(ExpressionResult::Value(exp)),
+        Expression::PopenHandle(_) => Ok(ExpressionResult::Value(exp)),
         _ if is_constant(exp.clone()) => Ok(ExpressionResult::Value(exp)),
         _ => Err(String::from("Not implemented yet.")),
     }
@@ -389,14 +392,18 @@ pub fn eval_function_call(
     env: &Environment<Expression>,
 ) -> Result<ExpressionResult, String> {
     // Check for built-in functions first
-    if let Some(result) = super::builtins::eval_builtin_function(&name, args.clone(), env)? {
-        return Ok(result);
+    match super::builtins::eval_builtin_function(&name, args.clone(), env) {
+        Ok(result) => return Ok(result),
+        Err(BuiltinError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
     }
     
     // If not a built-in function, look for user-defined functions
     match env.lookup_function(&name) {
         Some(function_definition) => {
             let mut new_env = Environment::new();
+            // Processes spawned with subprocess.Popen stay reachable inside the function
+            new_env.subprocess = env.subprocess.clone();
 
             if args.len() != function_definition.params.len() {
                 return Err(format!(
@@ -523,6 +530,7 @@ fn is_constant(exp: Expression) -> bool {
         Expression::CInt(_) => true,
         Expression::CReal(_) => true,
         Expression::CString(_) => true,
+        Expression::CBytes(_) => true,
         Expression::CNothing => true,
         _ => false,
     }
@@ -542,6 +550,25 @@ fn eval_list_value(
     Ok(ExpressionResult::Value(Expression::ListValue(values)))
 }
 
+fn eval_map_value(
+    entries: Vec<(Expression, Expression)>,
+    env: &Environment<Expression>,
+) -> Result<ExpressionResult, String> {
+    let mut values = Vec::new();
+    for (key, value) in entries {
+        let key = match eval(key, env)? {
+            ExpressionResult::Value(expr) => expr,
+            ExpressionResult::Propagate(expr) => return Ok(ExpressionResult::Propagate(expr)),
+        };
+        let value = match eval(value, env)? {
+            ExpressionResult::Value(expr) => expr,
+            ExpressionResult::Propagate(expr) => return Ok(ExpressionResult::Propagate(expr)),
+        };
+        values.push((key, value));
+    }
+    Ok(ExpressionResult::Value(Expression::MapValue(values)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1504,6 +1531,24 @@ mod tests {
             ]);
             assert_eq!(extract_value(result.unwrap()), expected);
         }
+
+        #[test]
+        fn test_map_with_expressions() {
+            let env = create_test_env();
+            let map_expr = Expression::MapValue(vec![(
+                Expression::CString("sum".to_string()),
+                Expression::Add(Box::new(Expression::CInt(1)), Box::new(Expression::CInt(2))),
+            )]);
+
+            let result = eval(map_expr, &env);
+
+            assert!(result.is_ok());
+            let expected = Expression::MapValue(vec![(
+                Expression::CString("sum".to_string()),
+                Expression::CInt(3),
+            )]);
+            assert_eq!(extract_value(result.unwrap()), expected);
+        }
     }
 
     mod boolean_expression_tests {
@@ -2622,4 +2667,47 @@ mod tests {
             );
         }
     }
+
+    mod builtin_calls {
+        use super::*;
+
+        /// Built-in failing with a structured error instead of a message
+        fn structured_error_stub(
+            _args: Vec<Expression>,
+            _env: &Environment<Expression>,
+        ) -> Result<ExpressionResult, String> {
+            Ok(ExpressionResult::Propagate(Expression::CErr(Box::new(Expression::MapValue(vec![
+                (Expression::CString("message".to_string()), Expression::CString("boom".to_string())),
+                (Expression::CString("code".to_string()), Expression::CInt(42)),
+            ])))))
+        }
+
+        #[test]
+        fn test_builtin_structured_error_propagates() {
+            let env = create_test_env();
+            let name = "test.structured_error".to_string();
+            crate::interpreter::builtins::reregister(&name, structured_error_stub);
+
+            let result = eval(Expression::FuncCall(name, vec![]), &env);
+            assert_eq!(
+                result,
+                Ok(ExpressionResult::Propagate(Expression::CErr(Box::new(Expression::MapValue(vec![
+                    (Expression::CString("message".to_string()), Expression::CString("boom".to_string())),
+                    (Expression::CString("code".to_string()), Expression::CInt(42)),
+                ])))))
+            );
+        }
+
+        #[test]
+        fn test_builtin_runtime_error_and_unknown_function() {
+            let env = create_test_env();
+
+            let result = eval(Expression::FuncCall("subprocess.run".to_string(), vec![]), &env);
+            assert!(result.unwrap_err().starts_with("subprocess.run()"));
+
+            // Not a built-in, so it is looked up among the user-defined functions
+            let result = eval(Expression::FuncCall("no_such_function".to_string(), vec![]), &env);
+            assert_eq!(result, Err("Function no_such_function not found".to_string()));
+        }
+    }
 }
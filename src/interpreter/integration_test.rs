@@ -4,6 +4,7 @@ mod integration_tests {
     use crate::ir::ast::Expression;
     use crate::interpreter::expression_eval::{eval, ExpressionResult};
     use crate::interpreter::builtins::register_builtins;
+    use crate::interpreter::builtins::tests::map_field;
 
     #[test]
     fn test_subprocess_run_integration() {
@@ -29,7 +30,7 @@ mod integration_tests {
 
         // Check that we get a CompletedProcess result
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_some());
                 assert!(stderr.is_some());
@@ -60,7 +61,7 @@ mod integration_tests {
 
         // Check that we get a CompletedProcess result
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_some());
                 assert!(stderr.is_some());
@@ -144,7 +145,7 @@ mod integration_tests {
         assert!(result.is_ok());
 
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_some());
                 assert!(stderr.is_some());
@@ -176,7 +177,7 @@ mod integration_tests {
         let result = eval(function_call, &env);
         assert!(result.is_ok());
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_none()); // capture_output defaults to False
                 assert!(stderr.is_none());
@@ -196,7 +197,7 @@ mod integration_tests {
         let result = eval(function_call, &env);
         assert!(result.is_ok());
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_none()); // capture_output defaults to False
                 assert!(stderr.is_none());
@@ -225,7 +226,7 @@ mod integration_tests {
         assert!(result.is_ok());
 
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_some());
                 assert!(stderr.is_some());
@@ -256,7 +257,7 @@ mod integration_tests {
         assert!(result.is_ok());
 
         match result.unwrap() {
-            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr }) => {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, stderr, .. }) => {
                 assert_eq!(returncode, 0);
                 assert!(stdout.is_some());
                 assert!(stderr.is_some());
@@ -337,4 +338,98 @@ mod integration_tests {
             _ => panic!("Expected CErr result for command not found"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_subprocess_run_shell_inner_command_not_found_integration() {
+        let mut env = Environment::new();
+        register_builtins(&mut env);
+
+        let function_call = Expression::FuncCall(
+            "subprocess.run".to_string(),
+            vec![
+                Expression::CString("nonexistent_inner_cmd".to_string()),
+                Expression::CTrue, // shell=True
+                Expression::CTrue, // capture_output=True
+            ],
+        );
+
+        // The shell launches, so the failure is a CompletedProcess, not a CErr
+        match eval(function_call, &env).unwrap() {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stderr, .. }) => {
+                assert_ne!(returncode, 0);
+                assert!(!stderr.unwrap().is_empty());
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_result_ok_integration() {
+        let mut env = Environment::new();
+        register_builtins(&mut env);
+
+        let run_result = |command: &str| {
+            Expression::FuncCall(
+                "subprocess.run_result".to_string(),
+                vec![Expression::CString(command.to_string()), Expression::CTrue], // shell=True
+            )
+        };
+
+        let is_error = Expression::IsError(Box::new(run_result("echo fine")));
+        assert_eq!(eval(is_error, &env), Ok(ExpressionResult::Value(Expression::CFalse)));
+
+        // unwrap gives back the CompletedProcess
+        let unwrapped = Expression::Unwrap(Box::new(run_result("echo fine")));
+        match eval(unwrapped, &env).unwrap() {
+            ExpressionResult::Value(Expression::CompletedProcess { returncode, stdout, .. }) => {
+                assert_eq!(returncode, 0);
+                assert_eq!(stdout, Some("fine\n".to_string()));
+            }
+            other => panic!("Expected CompletedProcess result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subprocess_run_result_err_integration() {
+        let mut env = Environment::new();
+        register_builtins(&mut env);
+
+        let run_result = |command: &str| {
+            Expression::FuncCall(
+                "subprocess.run_result".to_string(),
+                vec![Expression::CString(command.to_string()), Expression::CTrue], // shell=True
+            )
+        };
+
+        let is_error = Expression::IsError(Box::new(run_result("echo oops >&2; exit 3")));
+        assert_eq!(eval(is_error, &env), Ok(ExpressionResult::Value(Expression::CTrue)));
+
+        // `?` propagates the error record
+        let propagated = Expression::Propagate(Box::new(run_result("echo oops >&2; exit 3")));
+        match eval(propagated, &env).unwrap() {
+            ExpressionResult::Propagate(Expression::MapValue(entries)) => {
+                assert_eq!(map_field(&entries, "returncode"), Some(Expression::CInt(3)));
+                assert_eq!(map_field(&entries, "stderr"), Some(Expression::CString("oops\n".to_string())));
+                match map_field(&entries, "message") {
+                    Some(Expression::CString(message)) => assert!(message.contains("exit status 3")),
+                    other => panic!("Expected an error message, got {:?}", other),
+                }
+            }
+            other => panic!("Expected a propagated error record, got {:?}", other),
+        }
+
+        // A command that cannot start has no return code
+        let args = vec![Expression::CString("definitely_nonexistent_command_xyz".to_string())];
+        let launch_failure = Expression::FuncCall("subprocess.run_result".to_string(), args);
+        match eval(launch_failure, &env).unwrap() {
+            ExpressionResult::Value(Expression::CErr(record)) => match *record {
+                Expression::MapValue(entries) => assert!(entries.contains(&(
+                    Expression::CString("returncode".to_string()),
+                    Expression::CVoid
+                ))),
+                other => panic!("Expected an error record, got {:?}", other),
+            },
+            other => panic!("Expected CErr result, got {:?}", other),
+        }
+    }
+}
@@ -6,4 +6,4 @@ pub mod subprocess_errors;
 
 pub use expression_eval::eval;
 pub use statement_execute::{execute, run};
-pub use builtins::{register_builtins, eval_builtin_function};
+pub use builtins::{register_builtins, eval_builtin_function, BuiltinError};
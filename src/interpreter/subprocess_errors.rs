@@ -1,4 +1,5 @@
 use std::io; // Importa std::io::Error para facilitar a conversão de erros de I/O.
+use crate::stdlib::subprocess::SubprocessError as StdlibSubprocessError;
 
 /// Enum para representar os diferentes tipos de erros que podem ocorrer
 /// durante a execução de subprocessos.
@@ -18,33 +19,63 @@ pub enum SubprocessError {
     Other(String), // Para erros genéricos ou não mapeados.
 }
 
-// Implementa a conversão de SubprocessError para String.
-impl From<SubprocessError> for String {
-    fn from(err: SubprocessError) -> Self {
-        match err {
-            SubprocessError::CommandNotFound(cmd_info) => format!("Command not found: {}", cmd_info),
+impl std::fmt::Display for SubprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubprocessError::CommandNotFound(cmd_info) => write!(f, "Command not found: {}", cmd_info),
             SubprocessError::ExecutionFailed { command_name, exit_code, stdout, stderr } => {
-                format!(
+                write!(
+                    f,
                     "Command '{}' failed with exit code {:?}. Stdout: {:?}, Stderr: {:?}",
                     command_name, exit_code, stdout, stderr
                 )
             },
-            SubprocessError::IoError(msg) => format!("I/O Error: {}", msg),
-            SubprocessError::InvalidArguments(msg) => format!("Invalid arguments: {}", msg),
-            SubprocessError::PermissionDenied(msg) => format!("Permission denied: {}", msg),
-            SubprocessError::OutputCaptureError(msg) => format!("Output capture error: {}", msg),
-            SubprocessError::Other(msg) => format!("Subprocess Error: {}", msg),
+            SubprocessError::IoError(msg) => write!(f, "I/O Error: {}", msg),
+            SubprocessError::InvalidArguments(msg) => write!(f, "Invalid arguments: {}", msg),
+            SubprocessError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            SubprocessError::OutputCaptureError(msg) => write!(f, "Output capture error: {}", msg),
+            SubprocessError::Other(msg) => write!(f, "Subprocess Error: {}", msg),
         }
     }
 }
 
+impl std::error::Error for SubprocessError {}
+
+// Implementa a conversão de SubprocessError para String.
+impl From<SubprocessError> for String {
+    fn from(err: SubprocessError) -> Self {
+        err.to_string()
+    }
+}
+
 impl SubprocessError {
-    /// Converte um std::io::Error para um SubprocessError mais específico.
+    /// Converte um std::io::Error para um SubprocessError mais específico,
+    /// usando a tabela de `stdlib::subprocess::SubprocessError::from_io_error`.
     pub fn from_io_error(err: io::Error, command_name: &str) -> Self {
-        match err.kind() {
-            io::ErrorKind::NotFound => SubprocessError::CommandNotFound(command_name.to_string()),
-            io::ErrorKind::PermissionDenied => SubprocessError::PermissionDenied(command_name.to_string()),
-            _ => SubprocessError::IoError(format!("{}: {}", command_name, err)), // Usar IoError para outros erros
+        StdlibSubprocessError::from_io_error(err, command_name).into()
+    }
+}
+
+// Converte o erro de `stdlib::subprocess` para este enum.
+impl From<StdlibSubprocessError> for SubprocessError {
+    fn from(err: StdlibSubprocessError) -> Self {
+        match err {
+            StdlibSubprocessError::CommandNotFound(cmd) => SubprocessError::CommandNotFound(cmd),
+            StdlibSubprocessError::PermissionDenied(cmd) => SubprocessError::PermissionDenied(cmd),
+            StdlibSubprocessError::InvalidArguments(msg) => SubprocessError::InvalidArguments(msg),
+            StdlibSubprocessError::OutputCaptureError(msg) => SubprocessError::OutputCaptureError(msg),
+            StdlibSubprocessError::ExecutionFailed(msg) => SubprocessError::IoError(msg),
+            StdlibSubprocessError::CalledProcessError { command, returncode, stdout, stderr } => {
+                SubprocessError::ExecutionFailed {
+                    command_name: command,
+                    exit_code: returncode,
+                    stdout,
+                    stderr,
+                }
+            }
+            other @ (StdlibSubprocessError::TimeoutExpired { .. } | StdlibSubprocessError::Cancelled(_)) => {
+                SubprocessError::Other(other.to_string())
+            }
         }
     }
 }
@@ -92,6 +123,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_subprocess_error_from_io_error_interrupted() {
+        let io_err = Error::new(ErrorKind::Interrupted, "Interrupted system call (os error 4)");
+        let sub_err = SubprocessError::from_io_error(io_err, "slow_cmd");
+        assert_eq!(
+            sub_err,
+            SubprocessError::IoError("slow_cmd: Interrupted system call (os error 4)".to_string())
+        );
+    }
+
     #[test]
     fn test_subprocess_error_to_string_command_not_found() {
         let err = SubprocessError::CommandNotFound("nonexistent_cmd".to_string());
@@ -131,4 +172,16 @@ mod tests {
         let msg: String = err.into();
         assert_eq!(msg, "Output capture error: Failed to read output");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_subprocess_error_as_boxed_error() {
+        fn find_command() -> Result<(), Box<dyn std::error::Error>> {
+            Err(SubprocessError::CommandNotFound("nonexistent_cmd".to_string()))?;
+            Ok(())
+        }
+
+        let err = find_command().unwrap_err();
+        assert_eq!(err.to_string(), "Command not found: nonexistent_cmd");
+        assert!(err.downcast_ref::<SubprocessError>().is_some());
+    }
+}
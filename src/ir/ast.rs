@@ -81,6 +81,7 @@ pub enum Expression {
     CReal(f64),
     CString(String),
     CVoid,
+    CBytes(Vec<u8>),
 
     // Variable reference
     Var(Name),
@@ -120,15 +121,28 @@ pub enum Expression {
     // List value
     ListValue(Vec<Expression>),
 
+    // Map value, as ordered key/value pairs
+    MapValue(Vec<(Expression, Expression)>),
+
     // Constructor
     Constructor(Name, Vec<Box<Expression>>),
 
     // Subprocess result object
     CompletedProcess {
+        // Command line that was run
+        args: Vec<String>,
         returncode: i32,
         stdout: Option<String>,
         stderr: Option<String>,
+        // Signal that terminated the process, if any
+        signal: Option<i32>,
+        // Whether invalid UTF-8 was replaced while decoding stdout / stderr
+        stdout_lossy: bool,
+        stderr_lossy: bool,
     },
+
+    // Handle to a process spawned by subprocess.Popen
+    PopenHandle(i32),
 }
 
 // Represents statements in the AST
@@ -1,3 +1,5 @@
 pub mod subprocess;
+pub mod os;
+pub mod shutil;
 
-pub use subprocess::*;
\ No newline at end of file
+pub use subprocess::*;
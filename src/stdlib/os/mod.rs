@@ -0,0 +1,3 @@
+pub mod path;
+
+pub use path::*;
@@ -0,0 +1,87 @@
+/// Home directory of the current user, taken from the environment
+/// and falling back to the password database on Unix
+fn current_user_home() -> Option<String> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    if let Ok(home) = std::env::var(var) {
+        if !home.is_empty() {
+            return Some(home);
+        }
+    }
+    user_home_by_uid()
+}
+
+#[cfg(not(windows))]
+fn user_home_by_uid() -> Option<String> {
+    let user = nix::unistd::User::from_uid(nix::unistd::getuid()).ok()??;
+    Some(user.dir.to_string_lossy().to_string())
+}
+
+#[cfg(windows)]
+fn user_home_by_uid() -> Option<String> {
+    None
+}
+
+/// Home directory of the named user, looked up in the password database
+#[cfg(not(windows))]
+fn user_home_by_name(name: &str) -> Option<String> {
+    let user = nix::unistd::User::from_name(name).ok()??;
+    Some(user.dir.to_string_lossy().to_string())
+}
+
+#[cfg(windows)]
+fn user_home_by_name(_name: &str) -> Option<String> {
+    None
+}
+
+/// Replace a leading `~` or `~user` with the corresponding home directory,
+/// like Python's `os.path.expanduser`. Paths that don't start with `~`, or
+/// whose user can't be resolved, are returned unchanged.
+pub fn expanduser(path: &str) -> String {
+    if !path.starts_with('~') {
+        return path.to_string();
+    }
+
+    let separator = path.find(['/', '\\']).unwrap_or(path.len());
+    let (prefix, rest) = path.split_at(separator);
+
+    let home = if prefix == "~" {
+        current_user_home()
+    } else {
+        user_home_by_name(&prefix[1..])
+    };
+
+    match home {
+        Some(home) => format!("{}{}", home.trim_end_matches(['/', '\\']), rest),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expanduser_home() {
+        let home = current_user_home().expect("home directory should be known");
+        assert_eq!(expanduser("~/foo"), format!("{}/foo", home.trim_end_matches('/')));
+        assert_eq!(expanduser("~"), home.trim_end_matches('/'));
+    }
+
+    #[test]
+    fn test_expanduser_without_tilde_is_unchanged() {
+        assert_eq!(expanduser("/tmp/foo"), "/tmp/foo");
+        assert_eq!(expanduser("foo/~/bar"), "foo/~/bar");
+    }
+
+    #[test]
+    fn test_expanduser_unknown_user_is_unchanged() {
+        assert_eq!(expanduser("~no_such_user_12345/foo"), "~no_such_user_12345/foo");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_expanduser_named_user() {
+        let root_home = user_home_by_name("root").expect("root should have a home directory");
+        assert_eq!(expanduser("~root/x"), format!("{}/x", root_home.trim_end_matches('/')));
+    }
+}
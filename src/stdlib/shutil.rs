@@ -0,0 +1,130 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Whether the file at `path` can be run: a regular file with an execute bit on Unix
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Names to try for `name` in each directory: on Windows a name without an
+/// extension is tried with each `PATHEXT` extension, as `cmd.exe` does
+#[cfg(windows)]
+fn candidate_names(name: &str, pathext: Option<&OsStr>) -> Vec<String> {
+    if Path::new(name).extension().is_some() {
+        return vec![name.to_string()];
+    }
+    let extensions = pathext
+        .map(|pathext| pathext.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".to_string());
+    extensions
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| format!("{}{}", name, extension))
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn candidate_names(name: &str, _pathext: Option<&OsStr>) -> Vec<String> {
+    vec![name.to_string()]
+}
+
+/// Every executable named `name` in the directories of `search_path`, in
+/// search order, like running `which -a`. On Windows `pathext` lists the
+/// extensions tried, defaulting to the usual `PATHEXT` when it is `None`.
+/// A name with a directory part is not searched for: it is returned alone if
+/// it is executable.
+pub fn which_all_in(name: &str, search_path: &OsStr, pathext: Option<&OsStr>) -> Vec<PathBuf> {
+    if name.is_empty() {
+        return Vec::new();
+    }
+    if Path::new(name).components().count() > 1 {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) { vec![path] } else { Vec::new() };
+    }
+
+    let mut matches: Vec<PathBuf> = Vec::new();
+    for dir in std::env::split_paths(search_path) {
+        for candidate in candidate_names(name, pathext) {
+            let path = dir.join(candidate);
+            // A directory listed twice in PATH yields the same match once
+            if is_executable(&path) && !matches.contains(&path) {
+                matches.push(path);
+            }
+        }
+    }
+    matches
+}
+
+/// The executable that running `name` with the given `PATH` and `PATHEXT`
+/// would execute: the first match of `which_all_in`
+pub fn which_in(name: &str, search_path: &OsStr, pathext: Option<&OsStr>) -> Option<PathBuf> {
+    which_all_in(name, search_path, pathext).into_iter().next()
+}
+
+/// Every executable named `name` on the interpreter's `PATH`, in search order.
+/// The first one is what running `name` would execute.
+pub fn which_all(name: &str) -> Vec<PathBuf> {
+    let search_path = std::env::var_os("PATH").unwrap_or_default();
+    which_all_in(name, &search_path, std::env::var_os("PATHEXT").as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_which_all_in_returns_every_match_in_order() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = std::env::temp_dir().join(format!("r-python-which-all-{}", std::process::id()));
+        let (first, second, empty) = (base.join("first"), base.join("second"), base.join("empty"));
+        for dir in [&first, &second, &empty] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        for dir in [&first, &second] {
+            let tool = dir.join("mytool");
+            std::fs::write(&tool, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        // Not executable, so not a match
+        std::fs::write(empty.join("mytool"), "").unwrap();
+
+        let search_path = std::env::join_paths([&second, &empty, &first, &second]).unwrap();
+        let matches = which_all_in("mytool", &search_path, None);
+        let missing = which_all_in("no_such_tool", &search_path, None);
+        std::fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(matches, vec![second.join("mytool"), first.join("mytool")]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_which_in_follows_the_given_pathext() {
+        let dir = std::env::temp_dir().join(format!("r-python-which-pathext-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["mytool.BAT", "mytool.CMD"] {
+            std::fs::write(dir.join(name), "@echo off\r\n").unwrap();
+        }
+
+        let search_path = dir.clone().into_os_string();
+        let bat_first = which_in("mytool", &search_path, Some(OsStr::new(".BAT;.CMD")));
+        let cmd_only = which_in("mytool", &search_path, Some(OsStr::new(".CMD")));
+        let exe_only = which_in("mytool", &search_path, Some(OsStr::new(".EXE")));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(bat_first, Some(dir.join("mytool.BAT")));
+        assert_eq!(cmd_only, Some(dir.join("mytool.CMD")));
+        assert_eq!(exe_only, None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use super::process::{LineStream, PopenProcess};
+use super::runner::{CommandRunner, SystemRunner};
+use super::types::{OutputSink, RunOptions};
+
+/// Subprocess state owned by an interpreter environment,
+/// such as the processes spawned with subprocess.Popen.
+/// Processes and line streams share one handle counter, so a handle names only one of them.
+#[derive(Default)]
+pub struct SubprocessContext {
+    processes: HashMap<i32, PopenProcess>,
+    streams: HashMap<i32, LineStream>,
+    next_handle: i32,
+    allowed_commands: Vec<String>,
+    enforce_allowlist: bool,
+    default_options: RunOptions,
+    last_options: Option<RunOptions>,
+    /// Whether subprocess.run captures output by default, for shell commands
+    shell_capture_default: Option<bool>,
+    /// Whether subprocess.run captures output by default, for direct commands
+    direct_capture_default: Option<bool>,
+    /// Runner used instead of `SystemRunner`, if one was installed
+    runner: Option<Rc<dyn CommandRunner>>,
+    /// Sink receiving the captured output of every run, if one was installed
+    output_sink: Option<OutputSink>,
+}
+
+impl SubprocessContext {
+    /// Create an empty context
+    pub fn new() -> Self {
+        SubprocessContext::default()
+    }
+
+    /// Store a spawned process and return the handle that identifies it
+    pub fn add_process(&mut self, process: PopenProcess) -> i32 {
+        self.next_handle += 1;
+        self.processes.insert(self.next_handle, process);
+        self.next_handle
+    }
+
+    /// Look up a stored process by handle
+    pub fn process_mut(&mut self, handle: i32) -> Option<&mut PopenProcess> {
+        self.processes.get_mut(&handle)
+    }
+
+    /// Remove a stored process, handing ownership back to the caller
+    pub fn remove_process(&mut self, handle: i32) -> Option<PopenProcess> {
+        self.processes.remove(&handle)
+    }
+
+    /// Store a line stream and return the handle that identifies it
+    pub fn add_stream(&mut self, stream: LineStream) -> i32 {
+        self.next_handle += 1;
+        self.streams.insert(self.next_handle, stream);
+        self.next_handle
+    }
+
+    /// Look up a stored line stream by handle
+    pub fn stream_mut(&mut self, handle: i32) -> Option<&mut LineStream> {
+        self.streams.get_mut(&handle)
+    }
+
+    /// Remove a stored line stream; dropping it kills the process if still running
+    pub fn remove_stream(&mut self, handle: i32) -> Option<LineStream> {
+        self.streams.remove(&handle)
+    }
+
+    /// Remove every stored process and line stream, killing and waiting for
+    /// those still running. Returns how many were still running.
+    pub fn reap_all(&mut self) -> usize {
+        let processes = self.processes.drain().map(|(_, mut process)| process.reap());
+        let streams = self.streams.drain().map(|(_, mut stream)| stream.reap());
+        // A process that can't be killed or waited for has already exited
+        processes.chain(streams).filter(|reaped| matches!(reaped, Ok(true))).count()
+    }
+
+    /// Add a program to the allowlist used when enforcement is enabled
+    pub fn allow_command(&mut self, name: &str) {
+        if !self.allowed_commands.iter().any(|allowed| allowed == name) {
+            self.allowed_commands.push(name.to_string());
+        }
+    }
+
+    /// Enable or disable allowlist enforcement for commands run from this environment
+    pub fn set_enforce_allowlist(&mut self, enforce: bool) {
+        self.enforce_allowlist = enforce;
+    }
+
+    /// Copy the allowlist settings of this context into the options of a run
+    pub fn apply_allowlist(&self, options: &mut RunOptions) {
+        options.enforce_allowlist |= self.enforce_allowlist;
+        options.allowed_commands.extend(self.allowed_commands.iter().cloned());
+    }
+
+    /// Pass the captured output of every run from this context to `sink` as well
+    pub fn set_output_sink(&mut self, sink: OutputSink) {
+        self.output_sink = Some(sink);
+    }
+
+    /// Copy the output sink of this context into the options of a run, unless
+    /// the options already have one
+    pub fn apply_output_sink(&self, options: &mut RunOptions) {
+        options.output_sink = options.output_sink.or(self.output_sink);
+    }
+
+    /// Options that subprocess built-ins start from before applying per-call arguments
+    pub fn default_options(&self) -> &RunOptions {
+        &self.default_options
+    }
+
+    /// Replace the options that subprocess built-ins start from
+    pub fn set_default_options(&mut self, options: RunOptions) {
+        self.default_options = options;
+    }
+
+    /// Set whether subprocess.run captures output when the call doesn't say,
+    /// separately for shell and direct commands; `None` falls back to the
+    /// default options
+    pub fn set_capture_default(&mut self, shell: bool, capture: Option<bool>) {
+        if shell {
+            self.shell_capture_default = capture;
+        } else {
+            self.direct_capture_default = capture;
+        }
+    }
+
+    /// Whether subprocess.run captures output by default for this kind of command, if set
+    pub fn capture_default(&self, shell: bool) -> Option<bool> {
+        if shell {
+            self.shell_capture_default
+        } else {
+            self.direct_capture_default
+        }
+    }
+
+    /// Run the commands of this environment's built-ins with `runner`
+    pub fn set_runner(&mut self, runner: Rc<dyn CommandRunner>) {
+        self.runner = Some(runner);
+    }
+
+    /// Runner for the commands of this environment's built-ins
+    pub fn runner(&self) -> Rc<dyn CommandRunner> {
+        self.runner.clone().unwrap_or_else(|| Rc::new(SystemRunner))
+    }
+
+    /// Remember the effective options of the most recent built-in call
+    pub fn record_options(&mut self, options: &RunOptions) {
+        self.last_options = Some(options.clone());
+    }
+
+    /// Effective options of the most recent built-in call, if any
+    pub fn last_options(&self) -> Option<&RunOptions> {
+        self.last_options.as_ref()
+    }
+}
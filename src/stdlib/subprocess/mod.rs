@@ -1,5 +1,10 @@
 pub mod types;
 pub mod process;
+pub mod context;
+pub mod shlex;
+pub mod runner;
 
 pub use types::*;
-pub use process::*;
\ No newline at end of file
+pub use process::*;
+pub use context::*;
+pub use runner::*;
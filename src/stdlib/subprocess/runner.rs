@@ -0,0 +1,47 @@
+use super::process::{count_stdout_bytes, run_command, run_shell_command_with_args};
+use super::types::{CompletedProcess, RunOptions, StdioTarget, SubprocessError};
+
+/// Runs the commands of the subprocess built-ins. An environment uses
+/// `SystemRunner` unless another runner is installed with
+/// `SubprocessContext::set_runner`, e.g. one returning canned results so
+/// interpreter logic can be tested without spawning processes.
+pub trait CommandRunner {
+    /// Run a command to completion. With `options.shell` set the first element
+    /// is the script and the rest become its positional parameters.
+    fn run(&self, command: Vec<String>, options: RunOptions) -> Result<CompletedProcess, SubprocessError>;
+
+    /// Run a command to completion and return the number of bytes it wrote to
+    /// stdout. By default the output is captured with `run` and measured.
+    fn count_stdout_bytes(&self, command: Vec<String>, options: RunOptions) -> Result<u64, SubprocessError> {
+        let options = RunOptions { stdout: Some(StdioTarget::Pipe), text: false, ..options };
+        let completed_process = self.run(command, options)?;
+        let length = match (completed_process.stdout_bytes, completed_process.stdout) {
+            (Some(bytes), _) => bytes.len(),
+            (None, Some(text)) => text.len(),
+            (None, None) => 0,
+        };
+        Ok(length as u64)
+    }
+}
+
+/// Runs commands as real child processes
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: Vec<String>, options: RunOptions) -> Result<CompletedProcess, SubprocessError> {
+        if options.shell {
+            let mut command = command.into_iter();
+            let script = command.next().unwrap_or_default();
+            run_shell_command_with_args(script, command.collect(), options)
+        } else {
+            run_command(command, options)
+        }
+    }
+
+    /// Counts the output as it is read instead of keeping it in memory.
+    /// Shell commands are not supported.
+    fn count_stdout_bytes(&self, command: Vec<String>, options: RunOptions) -> Result<u64, SubprocessError> {
+        count_stdout_bytes(command, options)
+    }
+}
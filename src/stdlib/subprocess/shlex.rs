@@ -0,0 +1,161 @@
+use super::types::SubprocessError;
+
+/// Split a command line into arguments using POSIX shell quoting rules,
+/// like Python's `shlex.split`. Nothing is expanded: no variables, globs or
+/// command substitution.
+///
+/// - Whitespace separates arguments
+/// - Single quotes keep everything up to the closing quote literally
+/// - Double quotes keep their content, except that a backslash escapes `"`, `\`, `$` and `` ` ``
+/// - Outside quotes, a backslash escapes the next character
+pub fn split(line: &str) -> Result<Vec<String>, SubprocessError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    // Distinguishes an empty quoted argument ('') from no argument at all
+    let mut in_argument = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_argument {
+                    args.push(std::mem::take(&mut current));
+                    in_argument = false;
+                }
+            }
+            '\'' => {
+                in_argument = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(unclosed("single", line)),
+                    }
+                }
+            }
+            '"' => {
+                in_argument = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(unclosed("double", line)),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(unclosed("double", line)),
+                    }
+                }
+            }
+            '\\' => {
+                in_argument = true;
+                match chars.next() {
+                    Some(c) => current.push(c),
+                    None => {
+                        return Err(SubprocessError::InvalidArguments(format!(
+                            "command line ends with an escape character: {}",
+                            line
+                        )))
+                    }
+                }
+            }
+            c => {
+                in_argument = true;
+                current.push(c);
+            }
+        }
+    }
+
+    if in_argument {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Build an argv from a command template, splitting it with `split` and then
+/// replacing each `{}` placeholder, in order, with one of `values`.
+/// Values are substituted after splitting, so whatever they contain (spaces,
+/// quotes, shell metacharacters) stays inside the argument it was placed in.
+pub fn fill_template(template: &str, values: &[String]) -> Result<Vec<String>, SubprocessError> {
+    let args = split(template)?;
+    let placeholders: usize = args.iter().map(|arg| arg.matches("{}").count()).sum();
+    if placeholders != values.len() {
+        return Err(SubprocessError::InvalidArguments(format!(
+            "command template has {} placeholders but {} values were given: {}",
+            placeholders,
+            values.len(),
+            template
+        )));
+    }
+
+    let mut values = values.iter();
+    let args = args
+        .into_iter()
+        .map(|arg| {
+            let mut pieces = arg.split("{}");
+            let mut filled = pieces.next().unwrap_or_default().to_string();
+            for piece in pieces {
+                filled.push_str(values.next().map(String::as_str).unwrap_or_default());
+                filled.push_str(piece);
+            }
+            filled
+        })
+        .collect();
+    Ok(args)
+}
+
+fn unclosed(kind: &str, line: &str) -> SubprocessError {
+    SubprocessError::InvalidArguments(format!("unclosed {} quote in command line: {}", kind, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_on_whitespace() {
+        assert_eq!(split("git  status\t-s ").unwrap(), strings(&["git", "status", "-s"]));
+        assert_eq!(split("   ").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_split_quotes_and_escapes() {
+        assert_eq!(
+            split(r#"echo 'a b' "c \"d\" $e" f\ g ''"#).unwrap(),
+            strings(&["echo", "a b", "c \"d\" $e", "f g", ""])
+        );
+        assert_eq!(split(r#"pre'fix'"ed""#).unwrap(), strings(&["prefixed"]));
+    }
+
+    #[test]
+    fn test_split_rejects_unclosed_quotes() {
+        assert!(matches!(split("echo 'oops"), Err(SubprocessError::InvalidArguments(_))));
+        assert!(matches!(split("echo \"oops"), Err(SubprocessError::InvalidArguments(_))));
+        assert!(matches!(split("echo oops\\"), Err(SubprocessError::InvalidArguments(_))));
+    }
+
+    #[test]
+    fn test_fill_template_keeps_values_whole() {
+        let values = strings(&["a b; rm -rf /", "$(whoami)"]);
+        assert_eq!(
+            fill_template("grep -e {} '--label={}' file", &values).unwrap(),
+            strings(&["grep", "-e", "a b; rm -rf /", "--label=$(whoami)", "file"])
+        );
+    }
+
+    #[test]
+    fn test_fill_template_counts_placeholders() {
+        assert!(matches!(
+            fill_template("echo {} {}", &strings(&["one"])),
+            Err(SubprocessError::InvalidArguments(_))
+        ));
+    }
+}
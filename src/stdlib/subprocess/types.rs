@@ -1,14 +1,170 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct CompletedProcess {
-    pub returncode: i32,
+    /// Command line that was run: the program and its arguments, or the shell
+    /// and its arguments for a shell command. Empty for a pipeline.
+    pub args: Vec<String>,
+    /// Exit code of the child, or None if it did not exit normally (e.g. it was
+    /// terminated by a signal, which is then in `signal`)
+    pub returncode: Option<i32>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    /// Undecoded stdout, captured instead of `stdout` when `text` is disabled
+    pub stdout_bytes: Option<Vec<u8>>,
+    /// Undecoded stderr, captured instead of `stderr` when `text` is disabled
+    pub stderr_bytes: Option<Vec<u8>>,
+    /// Resource usage of the child, collected when the `rusage` option is set (Unix only)
+    pub rusage: Option<ResourceUsage>,
+    /// Signal that terminated the child, if it did not exit normally (Unix only)
+    pub signal: Option<i32>,
+    /// Whether invalid UTF-8 in `stdout` was replaced with U+FFFD while decoding
+    pub stdout_lossy: bool,
+    /// Whether invalid UTF-8 in `stderr` was replaced with U+FFFD while decoding
+    pub stderr_lossy: bool,
+}
+
+impl CompletedProcess {
+    /// Whether the child was terminated by a signal instead of exiting
+    pub fn signaled(&self) -> bool {
+        self.signal.is_some()
+    }
 }
 
+/// Resource usage of a finished child process
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResourceUsage {
+    /// Peak resident set size in kilobytes
+    pub max_rss_kb: i64,
+    /// CPU time spent in user mode, in milliseconds
+    pub user_ms: i64,
+    /// CPU time spent in the kernel, in milliseconds
+    pub sys_ms: i64,
+}
+
+/// Where a child's output stream is sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum StdioTarget {
+    /// Share the interpreter's stream
+    Inherit,
+    /// Capture the stream into the CompletedProcess
+    Pipe,
+    /// Discard the stream
+    Null,
+    /// Append the stream to a file, creating it if needed
+    File(String),
+}
+
+/// Receives each line of captured output as it is read from the child, without
+/// its line terminator. Called from the threads reading the child's streams.
+pub type OutputSink = fn(&str);
+
 #[derive(Debug, Clone)]
 pub struct RunOptions {
     pub shell: bool,
     pub capture_output: bool,
+    /// Environment variable overrides applied on top of the inherited environment.
+    /// Pairs are applied in order, so the last value for a repeated key wins.
+    pub env: Vec<(String, String)>,
+    /// When set, the child starts from an empty environment holding only these
+    /// variables copied from the interpreter; `env` overrides are applied on top
+    pub env_keep: Option<Vec<String>>,
+    /// Strip trailing whitespace from captured stdout and stderr
+    pub strip: bool,
+    /// Keep only the last N lines of captured stdout and stderr in memory
+    pub tail_lines: Option<usize>,
+    /// Kill the command and fail with `TimeoutExpired` if it runs longer than this
+    pub timeout: Option<Duration>,
+    /// Decode captured output as text; when false the raw bytes are kept
+    pub text: bool,
+    /// When decoding text, fail with `OutputCaptureError` on invalid UTF-8
+    /// instead of replacing the bad bytes with U+FFFD
+    pub strict: bool,
+    /// Encodings (WHATWG labels such as `"latin1"` or `"shift_jis"`) tried in order
+    /// when captured text is not valid UTF-8; the first that decodes cleanly wins
+    pub encoding_fallbacks: Vec<String>,
+    /// Kill a spawned process that is still running when its handle is dropped.
+    /// When false such a process is left running, silently, and is not reaped.
+    pub kill_on_drop: bool,
+    /// Niceness increment applied to the child before it starts (Unix only).
+    /// Lowering priority always works; negative values need privileges.
+    pub nice: Option<i32>,
+    /// Only allow commands whose program appears in `allowed_commands`
+    pub enforce_allowlist: bool,
+    /// Programs that may be run when `enforce_allowlist` is set
+    pub allowed_commands: Vec<String>,
+    /// Working directory for the child; inherits the current one when unset
+    pub cwd: Option<String>,
+    /// Name identifying the process in error messages
+    pub label: Option<String>,
+    /// Collect the child's resource usage when it exits (Unix only)
+    pub rusage: bool,
+    /// Attach the child to a pseudo-terminal and capture everything it writes
+    /// there as stdout (Unix only)
+    pub pty: bool,
+    /// Normalize `\r\n` and `\r` line endings to `\n` in captured text
+    pub universal_newlines: bool,
+    /// Kill the command and fail with `Cancelled` once this flag is set
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Where stdin comes from when neither `input` nor `input_file` is given;
+    /// when unset it is `Null`, so a command reading stdin gets EOF instead of
+    /// waiting on the terminal. A `File` target is read from the start.
+    pub stdin: Option<StdioTarget>,
+    /// Where stdout goes; when unset it is piped if `capture_output` is set
+    pub stdout: Option<StdioTarget>,
+    /// Where stderr goes; when unset it is piped if `capture_output` is set
+    pub stderr: Option<StdioTarget>,
+    /// Fail with `CalledProcessError` when the command exits with a status not
+    /// in `success_codes`.
+    /// stderr that would otherwise go to the terminal is captured so the error can
+    /// include it; when the command succeeds it is returned in `stderr`.
+    pub check: bool,
+    /// Bytes written verbatim to the child's stdin, which is then closed
+    pub input: Option<Vec<u8>>,
+    /// File connected to the child's stdin, so it reads the file directly without
+    /// the interpreter loading it into memory. Cannot be combined with `input`.
+    pub input_file: Option<String>,
+    /// When a command given as a single string is run without the shell, split
+    /// it into arguments with `shlex::split`; when false, the whole string is
+    /// the name of the program to run
+    pub split_string: bool,
+    /// Send stderr to wherever stdout goes, through the same descriptor (`2>&1`),
+    /// so captured output keeps the order in which the two were written.
+    /// The `stderr` target is then ignored.
+    pub merge_stderr: bool,
+    /// CPU time limit for the child, in seconds (`RLIMIT_CPU`, Unix only).
+    /// The child gets `SIGXCPU` once it is exceeded, and is killed a second later.
+    pub cpu_limit_secs: Option<u64>,
+    /// Address space limit for the child, in bytes (`RLIMIT_AS`, Unix only);
+    /// allocations beyond it fail
+    pub mem_limit_bytes: Option<u64>,
+    /// Pipe the stdin of a process spawned with `popen_command` so it can be
+    /// written to; when false stdin is routed like `run_command`'s
+    pub pipe_stdin: bool,
+    /// Start the child in a new session (`setsid`, Unix only), detached from the
+    /// interpreter's controlling terminal, so a Ctrl-C at the terminal does not
+    /// reach it and the interpreter decides what happens to it
+    pub new_session: bool,
+    /// Remove ANSI escape sequences (colors, cursor movement, terminal titles)
+    /// from captured text, so output of colorized tools compares as plain text
+    pub strip_ansi: bool,
+    /// User ID the child runs as (Unix only). Switching to another user needs
+    /// privileges; without them the command fails with `PermissionDenied`.
+    pub uid: Option<u32>,
+    /// Group ID the child runs as (Unix only), with the same privilege rules as `uid`
+    pub gid: Option<u32>,
+    /// Tag prepended to every line of captured stdout and stderr, e.g. `"[build] "`,
+    /// to tell apart the output of several processes logged together
+    pub line_prefix: Option<String>,
+    /// Exit codes that count as success for `check` and the built-ins that report
+    /// whether a command succeeded, e.g. `[0, 1]` for `grep`, which exits with 1
+    /// when nothing matched
+    pub success_codes: Vec<i32>,
+    /// Also pass every line of captured stdout and stderr to this sink as it
+    /// arrives, e.g. to forward it to an embedder's logger
+    pub output_sink: Option<OutputSink>,
 }
 
 impl Default for RunOptions {
@@ -16,10 +172,202 @@ impl Default for RunOptions {
         RunOptions {
             shell: false,
             capture_output: false,
+            env: Vec::new(),
+            env_keep: None,
+            strip: false,
+            tail_lines: None,
+            timeout: None,
+            text: true,
+            strict: false,
+            encoding_fallbacks: Vec::new(),
+            kill_on_drop: false,
+            nice: None,
+            enforce_allowlist: false,
+            allowed_commands: Vec::new(),
+            cwd: None,
+            label: None,
+            rusage: false,
+            pty: false,
+            universal_newlines: false,
+            cancel: None,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            check: false,
+            input: None,
+            input_file: None,
+            split_string: true,
+            merge_stderr: false,
+            cpu_limit_secs: None,
+            mem_limit_bytes: None,
+            pipe_stdin: true,
+            new_session: false,
+            strip_ansi: false,
+            uid: None,
+            gid: None,
+            line_prefix: None,
+            success_codes: vec![0],
+            output_sink: None,
         }
     }
 }
 
+impl RunOptions {
+    /// Whether a command with this exit code succeeded; one that has no exit
+    /// code never did
+    pub fn is_success(&self, returncode: Option<i32>) -> bool {
+        returncode.is_some_and(|code| self.success_codes.contains(&code))
+    }
+
+    /// Whether a command given as a single string is split into arguments
+    pub fn splits_command_string(&self) -> bool {
+        self.split_string && !self.shell
+    }
+
+    /// Where stdin comes from when no input is given, resolving the `Null` default
+    pub fn stdin_target(&self) -> StdioTarget {
+        self.stdin.clone().unwrap_or(StdioTarget::Null)
+    }
+
+    /// Where stdout goes, resolving the `capture_output` default
+    pub fn stdout_target(&self) -> StdioTarget {
+        self.stdout.clone().unwrap_or_else(|| self.default_target())
+    }
+
+    /// Where stderr goes, resolving the `capture_output` default
+    pub fn stderr_target(&self) -> StdioTarget {
+        self.stderr.clone().unwrap_or_else(|| self.default_target())
+    }
+
+    fn default_target(&self) -> StdioTarget {
+        if self.capture_output {
+            StdioTarget::Pipe
+        } else {
+            StdioTarget::Inherit
+        }
+    }
+
+    /// Value a variable will have in the child's environment: the last override
+    /// in `env`, else the interpreter's own value
+    pub fn child_env_var(&self, key: &str) -> Option<String> {
+        self.env
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.clone())
+            .or_else(|| std::env::var(key).ok())
+    }
+
+    /// Prepend a directory to the child's `PATH`, keeping the rest of it.
+    /// Builds on `env`, so a `PATH` override already set there is extended
+    /// instead of the interpreter's own `PATH`.
+    pub fn prepend_path(&mut self, dir: &str) {
+        let current = self.child_env_var("PATH").unwrap_or_default();
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        let path = if current.is_empty() {
+            dir.to_string()
+        } else {
+            format!("{}{}{}", dir, separator, current)
+        };
+        self.env.push(("PATH".to_string(), path));
+    }
+}
+
+/// Defines `PartialRunOptions`, with an optional field for every field of
+/// `RunOptions` listed, and `RunOptions::merge`, which applies the fields set in it
+macro_rules! partial_run_options {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        /// Options of a run where every field can be left unset, to build up
+        /// options in steps (see `RunOptions::merge`). Setting a field, even to
+        /// its default value, overrides it. `env` overrides and allowed commands
+        /// are added to those of the options merged into.
+        #[derive(Debug, Clone, Default)]
+        pub struct PartialRunOptions {
+            pub env: Vec<(String, String)>,
+            pub allowed_commands: Vec<String>,
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl PartialRunOptions {
+            /// Add an environment variable override
+            pub fn env(mut self, key: &str, value: &str) -> Self {
+                self.env.push((key.to_string(), value.to_string()));
+                self
+            }
+
+            /// Allow one more command when the allowlist is enforced
+            pub fn allow_command(mut self, name: &str) -> Self {
+                self.allowed_commands.push(name.to_string());
+                self
+            }
+
+            $(
+                #[doc = concat!("Set `", stringify!($field), "`")]
+                pub fn $field(mut self, value: $ty) -> Self {
+                    self.$field = Some(value);
+                    self
+                }
+            )*
+        }
+
+        impl RunOptions {
+            /// Combine options built up in steps, e.g. an embedder's base options
+            /// with per-call settings: every field set in `other` overrides the one
+            /// in `self`, and the `env` overrides and allowed commands of `other`
+            /// are added after those of `self`, so they win.
+            pub fn merge(mut self, other: PartialRunOptions) -> RunOptions {
+                self.env.extend(other.env);
+                self.allowed_commands.extend(other.allowed_commands);
+                $(
+                    if let Some(value) = other.$field {
+                        self.$field = value;
+                    }
+                )*
+                self
+            }
+        }
+    };
+}
+
+partial_run_options! {
+    shell: bool,
+    capture_output: bool,
+    env_keep: Option<Vec<String>>,
+    strip: bool,
+    tail_lines: Option<usize>,
+    timeout: Option<Duration>,
+    text: bool,
+    strict: bool,
+    encoding_fallbacks: Vec<String>,
+    kill_on_drop: bool,
+    nice: Option<i32>,
+    enforce_allowlist: bool,
+    cwd: Option<String>,
+    label: Option<String>,
+    rusage: bool,
+    pty: bool,
+    universal_newlines: bool,
+    cancel: Option<Arc<AtomicBool>>,
+    stdin: Option<StdioTarget>,
+    stdout: Option<StdioTarget>,
+    stderr: Option<StdioTarget>,
+    check: bool,
+    input: Option<Vec<u8>>,
+    input_file: Option<String>,
+    split_string: bool,
+    merge_stderr: bool,
+    cpu_limit_secs: Option<u64>,
+    mem_limit_bytes: Option<u64>,
+    pipe_stdin: bool,
+    new_session: bool,
+    strip_ansi: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    line_prefix: Option<String>,
+    success_codes: Vec<i32>,
+    output_sink: Option<OutputSink>,
+}
+
 /// Comprehensive error types for subprocess operations
 #[derive(Debug, Clone, PartialEq)]
 pub enum SubprocessError {
@@ -33,6 +381,18 @@ pub enum SubprocessError {
     ExecutionFailed(String),
     /// Error capturing command output
     OutputCaptureError(String),
+    /// Command was killed after running longer than its timeout
+    TimeoutExpired { command: String, seconds: f64 },
+    /// Command was killed because its cancellation flag was set
+    Cancelled(String),
+    /// Command did not succeed while the `check` option was set. `returncode` is
+    /// None if it did not exit normally, e.g. it was terminated by a signal.
+    CalledProcessError {
+        command: String,
+        returncode: Option<i32>,
+        stdout: Option<String>,
+        stderr: Option<String>,
+    },
 }
 
 impl std::fmt::Display for SubprocessError {
@@ -43,6 +403,22 @@ impl std::fmt::Display for SubprocessError {
             SubprocessError::PermissionDenied(cmd) => write!(f, "Permission denied: {}", cmd),
             SubprocessError::ExecutionFailed(msg) => write!(f, "Execution failed: {}", msg),
             SubprocessError::OutputCaptureError(msg) => write!(f, "Output capture error: {}", msg),
+            SubprocessError::TimeoutExpired { command, seconds } => {
+                write!(f, "Command '{}' timed out after {} seconds", command, seconds)
+            }
+            SubprocessError::Cancelled(cmd) => write!(f, "Command '{}' was cancelled", cmd),
+            SubprocessError::CalledProcessError { command, returncode, stderr, .. } => {
+                match returncode {
+                    Some(returncode) => {
+                        write!(f, "Command '{}' returned non-zero exit status {}", command, returncode)?
+                    }
+                    None => write!(f, "Command '{}' did not exit normally", command)?,
+                }
+                match stderr.as_deref().map(str::trim_end) {
+                    Some(stderr) if !stderr.is_empty() => write!(f, ": {}", stderr),
+                    _ => Ok(()),
+                }
+            }
         }
     }
 }
@@ -56,7 +432,21 @@ impl From<SubprocessError> for String {
     }
 }
 
-/// Convert std::io::Error to SubprocessError with context
+/// Convert std::io::Error to SubprocessError with context.
+/// This is the one mapping from I/O errors to subprocess errors:
+///
+/// | `ErrorKind`        | `SubprocessError`                           |
+/// |--------------------|---------------------------------------------|
+/// | `NotFound`         | `CommandNotFound(command)`                  |
+/// | `PermissionDenied` | `PermissionDenied(command)`                 |
+/// | `InvalidData`      | `OutputCaptureError` (undecodable output)   |
+/// | `TimedOut`         | `ExecutionFailed` (an OS-level timeout)     |
+/// | `Interrupted`      | `ExecutionFailed` (a signal interrupted it) |
+/// | `BrokenPipe`       | `ExecutionFailed` (the child closed a pipe) |
+/// | anything else      | `ExecutionFailed`                           |
+///
+/// `TimeoutExpired` and `Cancelled` are only raised by the code enforcing the
+/// `timeout` and `cancel` options, never derived from an I/O error.
 impl SubprocessError {
     pub fn from_io_error(error: std::io::Error, command: &str) -> Self {
         match error.kind() {
@@ -66,9 +456,92 @@ impl SubprocessError {
             std::io::ErrorKind::PermissionDenied => {
                 SubprocessError::PermissionDenied(command.to_string())
             }
+            std::io::ErrorKind::InvalidData => {
+                SubprocessError::OutputCaptureError(format!("{}: {}", command, error))
+            }
             _ => {
                 SubprocessError::ExecutionFailed(format!("{}: {}", command, error))
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn test_merge_keeps_fields_set_on_either_side() {
+        let base = RunOptions {
+            cwd: Some("/a".to_string()),
+            env: vec![("A".to_string(), "1".to_string())],
+            text: false,
+            ..Default::default()
+        };
+        let call = PartialRunOptions::default()
+            .capture_output(true)
+            .env("A", "2")
+            .timeout(Some(Duration::from_secs(5)));
+
+        let merged = base.merge(call);
+        assert_eq!(merged.cwd, Some("/a".to_string()));
+        assert!(merged.capture_output);
+        assert!(!merged.text);
+        assert_eq!(merged.timeout, Some(Duration::from_secs(5)));
+        assert_eq!(merged.child_env_var("A"), Some("2".to_string()));
+
+        // A field set on both sides takes the value from `other`
+        let merged = RunOptions { cwd: Some("/a".to_string()), ..Default::default() }
+            .merge(PartialRunOptions::default().cwd(Some("/b".to_string())));
+        assert_eq!(merged.cwd, Some("/b".to_string()));
+    }
+
+    #[test]
+    fn test_merge_can_reset_fields_to_their_defaults() {
+        let base = RunOptions {
+            capture_output: true,
+            check: true,
+            text: false,
+            split_string: false,
+            success_codes: vec![0, 1],
+            cwd: Some("/a".to_string()),
+            ..Default::default()
+        };
+        let call = PartialRunOptions::default()
+            .capture_output(false)
+            .check(false)
+            .text(true)
+            .split_string(true)
+            .success_codes(vec![0])
+            .cwd(None);
+
+        let merged = base.merge(call);
+        let defaults = RunOptions::default();
+        assert_eq!(merged.capture_output, defaults.capture_output);
+        assert_eq!(merged.check, defaults.check);
+        assert_eq!(merged.text, defaults.text);
+        assert_eq!(merged.split_string, defaults.split_string);
+        assert_eq!(merged.success_codes, defaults.success_codes);
+        assert_eq!(merged.cwd, None);
+    }
+
+    #[test]
+    fn test_from_io_error_mapping_table() {
+        let failed = || SubprocessError::ExecutionFailed("cmd: failed".to_string());
+        let cases = [
+            (ErrorKind::NotFound, SubprocessError::CommandNotFound("cmd".to_string())),
+            (ErrorKind::PermissionDenied, SubprocessError::PermissionDenied("cmd".to_string())),
+            (ErrorKind::InvalidData, SubprocessError::OutputCaptureError("cmd: failed".to_string())),
+            (ErrorKind::TimedOut, failed()),
+            (ErrorKind::Interrupted, failed()),
+            (ErrorKind::BrokenPipe, failed()),
+            (ErrorKind::Other, failed()),
+            (ErrorKind::WouldBlock, failed()),
+        ];
+        for (kind, expected) in cases {
+            let error = SubprocessError::from_io_error(Error::new(kind, "failed"), "cmd");
+            assert_eq!(error, expected, "mapping {:?}", kind);
+        }
+    }
 }
\ No newline at end of file